@@ -10,24 +10,87 @@ pub enum Actions {
     List(ListCmd),
     /// Export db
     Export(Export),
+    /// Print the entries of a database
+    Show(Show),
+    /// Print the entries matching a search
+    Search(Search),
+    /// Print the entries that log into a site, the best matches first
+    FindUrl(FindUrl),
+    /// Print a field of one entry
+    Get(Get),
+    /// Add an entry
+    Add(AddEntry),
+    /// Change the fields of an entry
+    Edit(EditEntry),
+    /// Remove an entry
+    Rm(RmEntry),
+    /// Change the password, keyfile, encryption or key derivation of a database
+    Rekey(Rekey),
+    /// Show or change the settings of a database
+    Config(Config),
+    /// Manage the key slots, each one unlocks the database on its own
+    Slot(SlotCmd),
+    /// Create an identity to open databases shared with you
+    Keygen(Keygen),
+    /// Let recipients open a database with their identity
+    Share(Share),
 }
 
 #[derive(Args)]
 pub struct Init {
     pub filename: String,
+    #[command(flatten)]
+    pub kdf: KdfArgs,
+}
+
+#[derive(Args)]
+pub struct KdfArgs {
+    /// Key derivation function: argon2id or scrypt
+    #[arg(long, default_value = "argon2id")]
+    pub kdf: String,
+    /// Memory cost in KiB
+    #[arg(long)]
+    pub kdf_memory: Option<u32>,
+    /// Number of passes (argon2id only)
+    #[arg(long)]
+    pub kdf_iterations: Option<u32>,
+    /// Degree of parallelism
+    #[arg(long)]
+    pub kdf_parallelism: Option<u32>,
 }
 
 #[derive(Args)]
 pub struct Opendb {
     pub filename: String,
+    /// Only needed for vaults without a header
     #[arg(short)]
-    pub encryption: String,
+    pub encryption: Option<String>,
     #[arg(long)]
     pub file: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+/// Where the master password comes from when it isn't asked in the terminal
+#[derive(Args)]
+#[group(multiple = false)]
+pub struct PasswordArgs {
+    /// Read the master password from the first line of stdin
+    #[arg(long)]
+    pub password_stdin: bool,
+    /// Read the master password from an environment variable, other processes may see it
+    #[arg(long, value_name = "VAR")]
+    pub password_env: Option<String>,
+    /// Read the master password from an open file descriptor
+    #[arg(long, value_name = "N")]
+    pub password_fd: Option<u32>,
+    /// Run a command and use the first line of its output as the master password
+    #[arg(long, value_name = "CMD")]
+    pub password_command: Option<String>,
 }
 
 #[derive(Args)]
-#[group(required = true, multiple = false)]
+#[group(id = "list", required = true, multiple = false, args = ["encryption", "formatexport"])]
 pub struct ListCmd {
     #[arg(short)]
     pub encryption: bool,
@@ -35,14 +98,306 @@ pub struct ListCmd {
     pub formatexport: bool,
 }
 
+/// How read commands print their result, accepted before or after the command
+#[derive(Args)]
+pub struct OutputArgs {
+    /// table, json, jsonl or tsv
+    #[arg(long, global = true, default_value = "table")]
+    pub output: String,
+    /// Print the passwords, they are masked otherwise
+    #[arg(long, global = true)]
+    pub reveal: bool,
+    /// Add the creation, modification and password change dates to the entries
+    #[arg(long, global = true)]
+    pub timestamps: bool,
+}
+
 #[derive(Args)]
 pub struct Export {
     pub input: String,
+    #[arg(id = "destination", value_name = "OUTPUT")]
     pub output: String,
     #[arg(short, long)]
     pub format: String,
+    /// Only needed for vaults without a header
     #[arg(short)]
-    pub encryption: String,
+    pub encryption: Option<String>,
     #[arg(short, long)]
     pub keyfile: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args)]
+pub struct Rekey {
+    pub filename: String,
+    /// Only needed for vaults without a header
+    #[arg(short)]
+    pub encryption: Option<String>,
+    /// The vault is currently opened with a keyfile
+    #[arg(long)]
+    pub file: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+    /// Encryption to switch to, the current one is kept if omitted
+    #[arg(long)]
+    pub new_encryption: Option<String>,
+    #[command(flatten)]
+    pub kdf: KdfArgs,
+}
+
+#[derive(Args)]
+pub struct Config {
+    pub filename: String,
+    /// How many previous passwords each entry keeps
+    #[arg(long)]
+    pub max_history: Option<usize>,
+    /// Only needed for vaults without a header
+    #[arg(short)]
+    pub encryption: Option<String>,
+    /// The vault is opened with a keyfile
+    #[arg(long)]
+    pub file: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args)]
+pub struct SlotCmd {
+    #[command(subcommand)]
+    pub action: SlotActions,
+}
+
+#[derive(Subcommand)]
+pub enum SlotActions {
+    /// Add a password or keyfile that unlocks the database
+    Add(SlotAdd),
+    /// Remove a key slot
+    Remove(SlotRemove),
+    /// List the key slots
+    List(SlotList),
+}
+
+#[derive(Args)]
+pub struct SlotAdd {
+    pub filename: String,
+    /// Only needed for vaults without a header
+    #[arg(short)]
+    pub encryption: Option<String>,
+    /// The vault is opened with a keyfile
+    #[arg(long)]
+    pub file: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+    #[command(flatten)]
+    pub kdf: KdfArgs,
+}
+
+#[derive(Args)]
+pub struct SlotRemove {
+    pub filename: String,
+    /// Index shown by `kmh slot list`
+    pub index: usize,
+    /// The vault is opened with a keyfile
+    #[arg(long)]
+    pub file: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args)]
+pub struct SlotList {
+    pub filename: String,
+    /// Only needed for vaults without a header
+    #[arg(short)]
+    pub encryption: Option<String>,
+}
+
+#[derive(Args)]
+pub struct Keygen {
+    /// Identity file to create, keep it private
+    pub output: String,
+}
+
+#[derive(Args)]
+pub struct Share {
+    pub filename: String,
+    /// Public key printed by `kmh keygen`, can be repeated
+    #[arg(short, long, required = true)]
+    pub recipient: Vec<String>,
+    /// Write a copy only the recipients can open instead of adding them to the database
+    #[arg(short = 'o', long, value_name = "OUTPUT")]
+    pub copy: Option<String>,
+    /// Overwrite the file given to --copy if it exists
+    #[arg(long, requires = "copy")]
+    pub force: bool,
+    /// Only needed for vaults without a header
+    #[arg(short)]
+    pub encryption: Option<String>,
+    /// The vault is opened with a keyfile
+    #[arg(long)]
+    pub file: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args)]
+pub struct Show {
+    pub filename: String,
+    /// Only the entries of this group and its subgroups, like `Work/AWS`
+    #[arg(long)]
+    pub group: Option<String>,
+    /// Only the entries with this tag
+    #[arg(long)]
+    pub tag: Option<String>,
+    /// Only needed for vaults without a header
+    #[arg(short)]
+    pub encryption: Option<String>,
+    /// The vault is opened with a keyfile
+    #[arg(long)]
+    pub file: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args)]
+pub struct Search {
+    pub filename: String,
+    /// Searched in the title, username, notes, URLs, tags and unprotected custom fields
+    pub query: String,
+    /// substring, regex or fuzzy
+    #[arg(long, default_value = "substring")]
+    pub mode: String,
+    /// Only needed for vaults without a header
+    #[arg(short)]
+    pub encryption: Option<String>,
+    /// The vault is opened with a keyfile
+    #[arg(long)]
+    pub file: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args)]
+pub struct FindUrl {
+    pub filename: String,
+    /// Address of the page, https is assumed without a scheme
+    pub url: String,
+    /// Only needed for vaults without a header
+    #[arg(short)]
+    pub encryption: Option<String>,
+    /// The vault is opened with a keyfile
+    #[arg(long)]
+    pub file: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args)]
+pub struct Get {
+    pub filename: String,
+    /// ID, title or part of the title
+    pub entry: String,
+    /// password, username, notes, group, urls, tags, all or a custom field
+    #[arg(long, default_value = "password")]
+    pub field: String,
+    /// Copy the field to the clipboard instead of printing it
+    #[arg(long)]
+    pub clip: bool,
+    /// Only needed for vaults without a header
+    #[arg(short)]
+    pub encryption: Option<String>,
+    /// The vault is opened with a keyfile
+    #[arg(long)]
+    pub file: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args)]
+pub struct EntryArgs {
+    #[arg(long)]
+    pub title: Option<String>,
+    #[arg(long)]
+    pub username: Option<String>,
+    #[arg(long)]
+    pub notes: Option<String>,
+    /// Read the password of the entry from stdin
+    #[arg(long, conflicts_with_all = ["generate", "password_stdin"])]
+    pub stdin: bool,
+    /// Generate a random password for the entry
+    #[arg(long)]
+    pub generate: bool,
+    /// Length of the generated password
+    #[arg(long, default_value_t = 20, requires = "generate")]
+    pub length: usize,
+    /// Group of the entry, like `Work/AWS/prod`, `/` is the root
+    #[arg(long)]
+    pub group: Option<String>,
+    /// Add a tag, can be repeated
+    #[arg(long)]
+    pub tag: Vec<String>,
+    /// Remove a tag, can be repeated
+    #[arg(long, value_name = "TAG")]
+    pub remove_tag: Vec<String>,
+    /// Site of the entry, https is assumed without a scheme. Can be repeated
+    #[arg(long)]
+    pub url: Vec<String>,
+    /// Remove a URL, can be repeated
+    #[arg(long, value_name = "URL")]
+    pub remove_url: Vec<String>,
+    /// Set a custom field, the type is text, hidden, url, email or number (text by default),
+    /// `!` masks it like the password. Can be repeated
+    #[arg(long, value_name = "[!][TYPE:]NAME=VALUE")]
+    pub custom: Vec<String>,
+    /// Remove a custom field, can be repeated
+    #[arg(long, value_name = "NAME")]
+    pub remove_custom: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct AddEntry {
+    pub filename: String,
+    #[command(flatten)]
+    pub entry: EntryArgs,
+    /// Only needed for vaults without a header
+    #[arg(short)]
+    pub encryption: Option<String>,
+    /// The vault is opened with a keyfile
+    #[arg(long)]
+    pub file: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args)]
+pub struct EditEntry {
+    pub filename: String,
+    /// ID, UUID or title
+    pub entry: String,
+    #[command(flatten)]
+    pub fields: EntryArgs,
+    /// Only needed for vaults without a header
+    #[arg(short)]
+    pub encryption: Option<String>,
+    /// The vault is opened with a keyfile
+    #[arg(long)]
+    pub file: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
+}
+
+#[derive(Args)]
+pub struct RmEntry {
+    pub filename: String,
+    /// ID, UUID or title
+    pub entry: String,
+    /// Only needed for vaults without a header
+    #[arg(short)]
+    pub encryption: Option<String>,
+    /// The vault is opened with a keyfile
+    #[arg(long)]
+    pub file: bool,
+    #[command(flatten)]
+    pub password: PasswordArgs,
 }
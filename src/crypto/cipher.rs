@@ -0,0 +1,293 @@
+use std::marker::PhantomData;
+
+use aes_gcm::{
+    aead::{self, Aead, KeyInit},
+    Aes256Gcm, Error,
+};
+use chacha20::ChaCha20;
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
+use crypto_secretbox::XSalsa20Poly1305;
+use ring::{
+    constant_time,
+    digest::{digest, SHA256},
+};
+use salsa20::{
+    cipher::{KeyIvInit, StreamCipher},
+    Salsa20,
+};
+
+/// A cipher a vault can be encrypted with.
+///
+/// To add a cipher implement this trait and append it to `REGISTRY`,
+/// `kmh init`, `kmh open -e`, `kmh export -e` and `kmh list -e` pick it up from there.
+pub trait VaultCipher: Sync {
+    /// Identifier stored in the vault header, must never change once released
+    fn id(&self) -> u8;
+    /// Name used by `-e` and `kmh list -e`
+    fn name(&self) -> &'static str;
+    /// Other accepted names for `-e`
+    fn aliases(&self) -> &'static [&'static str] {
+        &[]
+    }
+    /// Name shown in the `kmh init` menu
+    fn label(&self) -> &'static str;
+    /// At most 32 bytes, the size of the derived vault key
+    fn key_size(&self) -> usize;
+    fn nonce_size(&self) -> usize;
+    /// Cipher used instead of this one when the vault is saved, for ciphers only kept to read old vaults
+    fn replaced_by(&self) -> Option<&'static dyn VaultCipher> {
+        None
+    }
+    /// `aad` is authenticated with the ciphertext without being encrypted
+    fn encrypt(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, Error>;
+    fn decrypt(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, Error>;
+}
+
+pub struct AeadCipher<C> {
+    id: u8,
+    name: &'static str,
+    aliases: &'static [&'static str],
+    label: &'static str,
+    /// The cipher has no associated data (XSalsa20-Poly1305 is NaCl's secretbox),
+    /// the SHA-256 of `aad` is put in front of the plaintext instead
+    aad_in_plaintext: bool,
+    cipher: PhantomData<fn() -> C>,
+}
+
+impl<C: KeyInit + Aead> VaultCipher for AeadCipher<C> {
+    fn id(&self) -> u8 {
+        self.id
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn aliases(&self) -> &'static [&'static str] {
+        self.aliases
+    }
+
+    fn label(&self) -> &'static str {
+        self.label
+    }
+
+    fn key_size(&self) -> usize {
+        C::key_size()
+    }
+
+    fn nonce_size(&self) -> usize {
+        aead::Nonce::<C>::default().len()
+    }
+
+    fn encrypt(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let cipher = C::new_from_slice(key).map_err(|_| Error)?;
+        let nonce = aead::Nonce::<C>::from_slice(nonce);
+
+        if self.aad_in_plaintext && !aad.is_empty() {
+            let mut msg = digest(&SHA256, aad).as_ref().to_vec();
+            msg.extend_from_slice(plaintext);
+            return cipher.encrypt(nonce, &msg[..]);
+        }
+
+        let payload = aead::Payload {
+            msg: plaintext,
+            aad,
+        };
+        cipher.encrypt(nonce, payload)
+    }
+
+    fn decrypt(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let cipher = C::new_from_slice(key).map_err(|_| Error)?;
+        let nonce = aead::Nonce::<C>::from_slice(nonce);
+
+        if self.aad_in_plaintext && !aad.is_empty() {
+            let mut msg = cipher.decrypt(nonce, ciphertext)?;
+            let aad_len = SHA256.output_len;
+            if msg.len() < aad_len {
+                return Err(Error);
+            }
+            constant_time::verify_slices_are_equal(&msg[..aad_len], digest(&SHA256, aad).as_ref())
+                .map_err(|_| Error)?;
+            return Ok(msg.split_off(aad_len));
+        }
+
+        let payload = aead::Payload {
+            msg: ciphertext,
+            aad,
+        };
+        cipher.decrypt(nonce, payload)
+    }
+}
+
+/// Salsa20 and ChaCha20 without authentication, only kept to read vaults
+/// written by older versions. They have no header, so nothing to authenticate in `aad`
+pub struct LegacyStreamCipher<C> {
+    id: u8,
+    name: &'static str,
+    nonce_size: usize,
+    replaced_by: &'static dyn VaultCipher,
+    cipher: PhantomData<fn() -> C>,
+}
+
+impl<C: KeyIvInit + StreamCipher> VaultCipher for LegacyStreamCipher<C> {
+    fn id(&self) -> u8 {
+        self.id
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn label(&self) -> &'static str {
+        self.name
+    }
+
+    fn key_size(&self) -> usize {
+        C::key_size()
+    }
+
+    fn nonce_size(&self) -> usize {
+        self.nonce_size
+    }
+
+    fn replaced_by(&self) -> Option<&'static dyn VaultCipher> {
+        Some(self.replaced_by)
+    }
+
+    fn encrypt(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        _aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        let mut data = plaintext.to_vec();
+        let mut cipher = C::new_from_slices(key, nonce).map_err(|_| Error)?;
+
+        cipher.apply_keystream(&mut data[..]);
+
+        Ok(data)
+    }
+
+    fn decrypt(
+        &self,
+        key: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, Error> {
+        self.encrypt(key, nonce, aad, ciphertext)
+    }
+}
+
+pub static AES256GCM: AeadCipher<Aes256Gcm> = AeadCipher {
+    id: 1,
+    name: "aes256",
+    aliases: &["aes256-gcm", "aes"],
+    label: "AES256 GCM",
+    aad_in_plaintext: false,
+    cipher: PhantomData,
+};
+
+pub static CHACHA20POLY1305: AeadCipher<ChaCha20Poly1305> = AeadCipher {
+    id: 4,
+    name: "chacha20-poly1305",
+    aliases: &["chacha20poly1305"],
+    label: "Chacha20-Poly1305",
+    aad_in_plaintext: false,
+    cipher: PhantomData,
+};
+
+pub static XCHACHA20POLY1305: AeadCipher<XChaCha20Poly1305> = AeadCipher {
+    id: 5,
+    name: "xchacha20-poly1305",
+    aliases: &["xchacha20poly1305", "xchacha20"],
+    label: "XChacha20-Poly1305",
+    aad_in_plaintext: false,
+    cipher: PhantomData,
+};
+
+pub static XSALSA20POLY1305: AeadCipher<XSalsa20Poly1305> = AeadCipher {
+    id: 6,
+    name: "xsalsa20-poly1305",
+    aliases: &["xsalsa20poly1305", "xsalsa20"],
+    label: "XSalsa20-Poly1305",
+    aad_in_plaintext: true,
+    cipher: PhantomData,
+};
+
+pub static SALSA20: LegacyStreamCipher<Salsa20> = LegacyStreamCipher {
+    id: 2,
+    name: "salsa20",
+    nonce_size: 8,
+    replaced_by: &XSALSA20POLY1305,
+    cipher: PhantomData,
+};
+
+pub static CHACHA20: LegacyStreamCipher<ChaCha20> = LegacyStreamCipher {
+    id: 3,
+    name: "chacha20",
+    nonce_size: 12,
+    replaced_by: &CHACHA20POLY1305,
+    cipher: PhantomData,
+};
+
+static REGISTRY: [&dyn VaultCipher; 6] = [
+    &AES256GCM,
+    &CHACHA20POLY1305,
+    &XCHACHA20POLY1305,
+    &XSALSA20POLY1305,
+    &SALSA20,
+    &CHACHA20,
+];
+
+/// Every known cipher, including the ones only kept to read old vaults
+pub fn registry() -> &'static [&'static dyn VaultCipher] {
+    &REGISTRY
+}
+
+/// Ciphers new vaults can be encrypted with
+pub fn available() -> impl Iterator<Item = &'static dyn VaultCipher> {
+    registry()
+        .iter()
+        .copied()
+        .filter(|c| c.replaced_by().is_none())
+}
+
+/// Find a cipher by name or alias, case insensitive
+pub fn find(name: &str) -> Option<&'static dyn VaultCipher> {
+    let name = name.to_lowercase();
+
+    registry()
+        .iter()
+        .copied()
+        .find(|c| c.name() == name || c.aliases().contains(&name.as_str()))
+}
+
+pub fn by_id(id: u8) -> Option<&'static dyn VaultCipher> {
+    registry().iter().copied().find(|c| c.id() == id)
+}
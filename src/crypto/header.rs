@@ -0,0 +1,269 @@
+use std::fmt;
+
+use super::{
+    cipher::{self, VaultCipher},
+    kdf::Kdf,
+    slot::{KeySlot, WrappedKey},
+};
+use crate::LoginType;
+
+// Layout (all integers little-endian):
+//
+//   magic      4 bytes   "KMH\0"
+//   version    1 byte
+//   cipher     1 byte    see `VaultCipher::id`
+//   slot count 1 byte
+//   slots      ...       see below
+//   nonce      1 byte length + bytes
+//   ciphertext ...     the bytes before it are its associated data (since version 4)
+//
+// Key slot:
+//
+//   login       1 byte    see `LoginType::id`
+//   kdf         1 byte    see `Kdf::id`
+//   kdf params  12 bytes  3 x u32, see `Kdf::params`
+//   salt        1 byte length + bytes
+//   wrap nonce  1 byte length + bytes
+//   wrapped key 1 byte length + bytes, see `slot::WRAP_CIPHER`
+//
+// Versions 1 and 2 had no slots: kdf, kdf params, login (since version 2) and salt
+// came right after the cipher and the derived key was the vault key.
+// Version 3 didn't authenticate the header with the ciphertext.
+pub const MAGIC: &[u8; 4] = b"KMH\0";
+pub const FORMAT_VERSION: u8 = 4;
+/// The slot count is a single byte
+pub const MAX_SLOTS: usize = u8::MAX as usize;
+
+pub struct VaultHeader {
+    pub cipher: &'static dyn VaultCipher,
+    pub slots: Vec<KeySlot>,
+    pub nonce: Vec<u8>,
+    /// Bytes of the header the ciphertext was authenticated with, empty before version 4
+    pub authenticated: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum HeaderError {
+    /// The file doesn't start with the magic bytes (old headerless vault)
+    Missing,
+    Truncated,
+    UnsupportedVersion(u8),
+    UnknownCipher(u8),
+    /// Salsa20 or ChaCha20 without authentication, only found in vaults without a header
+    LegacyCipher(u8),
+    UnknownKdf(u8),
+    /// Key derivation parameters above the limits of `Kdf::check_cost`
+    KdfTooCostly(String),
+    UnknownLoginType(u8),
+    InvalidNonce,
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::Missing => write!(f, "Vault header not found"),
+            HeaderError::Truncated => write!(f, "Vault header is truncated"),
+            HeaderError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported vault format version: {}", v)
+            }
+            HeaderError::UnknownCipher(id) => {
+                write!(f, "Unknown cipher id in vault header: {}", id)
+            }
+            HeaderError::LegacyCipher(id) => {
+                write!(f, "Unauthenticated cipher id in vault header: {}", id)
+            }
+            HeaderError::UnknownKdf(id) => write!(f, "Unknown kdf id in vault header: {}", id),
+            HeaderError::KdfTooCostly(e) => {
+                write!(f, "Key derivation in vault header is too costly: {}", e)
+            }
+            HeaderError::UnknownLoginType(id) => {
+                write!(f, "Unknown login type in vault header: {}", id)
+            }
+            HeaderError::InvalidNonce => write!(f, "Invalid nonce length in vault header"),
+        }
+    }
+}
+
+impl VaultHeader {
+    /// Settings implied by a vault written before headers existed
+    pub fn legacy(cipher: &'static dyn VaultCipher) -> Self {
+        VaultHeader {
+            cipher,
+            slots: vec![KeySlot {
+                login: None,
+                kdf: Kdf::Sha256,
+                salt: Vec::new(),
+                wrapped_key: None,
+            }],
+            nonce: vec![0; cipher.nonce_size()],
+            authenticated: Vec::new(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        out.extend_from_slice(MAGIC);
+        out.push(FORMAT_VERSION);
+        out.push(self.cipher.id());
+        // `Keyring` never holds more than `MAX_SLOTS`
+        out.push(u8::try_from(self.slots.len()).expect("Too many key slots"));
+        for slot in &self.slots {
+            // Old vaults are moved to wrapped keys before being saved
+            let wrapped = slot
+                .wrapped_key
+                .as_ref()
+                .expect("Key slot without wrapped key");
+
+            out.push(slot.login.map_or(0, |l| l.id()));
+            out.push(slot.kdf.id());
+            for param in slot.kdf.params() {
+                out.extend_from_slice(&param.to_le_bytes());
+            }
+            push_bytes(&mut out, &slot.salt);
+            push_bytes(&mut out, &wrapped.nonce);
+            push_bytes(&mut out, &wrapped.key);
+        }
+        push_bytes(&mut out, &self.nonce);
+
+        out
+    }
+
+    /// Split a vault file into its header and the ciphertext that follows it
+    pub fn parse(data: &[u8]) -> Result<(VaultHeader, &[u8]), HeaderError> {
+        if !data.starts_with(MAGIC) {
+            return Err(HeaderError::Missing);
+        }
+
+        let mut reader = Reader {
+            data,
+            pos: MAGIC.len(),
+        };
+
+        let version = reader.byte()?;
+        if version == 0 || version > FORMAT_VERSION {
+            return Err(HeaderError::UnsupportedVersion(version));
+        }
+
+        let cipher_id = reader.byte()?;
+        let cipher = cipher::by_id(cipher_id).ok_or(HeaderError::UnknownCipher(cipher_id))?;
+        // Nothing would detect a change of the header or the data
+        if cipher.replaced_by().is_some() {
+            return Err(HeaderError::LegacyCipher(cipher_id));
+        }
+
+        let slots = match version {
+            1 | 2 => {
+                let kdf = reader.kdf()?;
+                let login = match version {
+                    1 => None,
+                    _ => reader.login()?,
+                };
+                let salt = reader.bytes()?.to_vec();
+
+                vec![KeySlot {
+                    login,
+                    kdf,
+                    salt,
+                    wrapped_key: None,
+                }]
+            }
+            _ => {
+                let count = reader.byte()?;
+                let mut slots = Vec::new();
+
+                for _ in 0..count {
+                    let login = reader.login()?;
+                    let kdf = reader.kdf()?;
+                    let salt = reader.bytes()?.to_vec();
+                    let nonce = reader.bytes()?.to_vec();
+                    let key = reader.bytes()?.to_vec();
+
+                    slots.push(KeySlot {
+                        login,
+                        kdf,
+                        salt,
+                        wrapped_key: Some(WrappedKey { nonce, key }),
+                    });
+                }
+
+                slots
+            }
+        };
+
+        let nonce = reader.bytes()?.to_vec();
+        if nonce.len() != cipher.nonce_size() {
+            return Err(HeaderError::InvalidNonce);
+        }
+
+        let authenticated = match version {
+            1..=3 => Vec::new(),
+            _ => data[..reader.pos].to_vec(),
+        };
+        let header = VaultHeader {
+            cipher,
+            slots,
+            nonce,
+            authenticated,
+        };
+
+        Ok((header, &data[reader.pos..]))
+    }
+}
+
+/// Salts, nonces and wrapped keys are all far below 255 bytes
+fn push_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.push(u8::try_from(bytes.len()).expect("Header field longer than 255 bytes"));
+    out.extend_from_slice(bytes);
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], HeaderError> {
+        let end = self.pos + len;
+        if end > self.data.len() {
+            return Err(HeaderError::Truncated);
+        }
+
+        let slice = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, HeaderError> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// 1 byte length + bytes
+    fn bytes(&mut self) -> Result<&'a [u8], HeaderError> {
+        let len = self.byte()? as usize;
+        self.take(len)
+    }
+
+    fn kdf(&mut self) -> Result<Kdf, HeaderError> {
+        let kdf_id = self.byte()?;
+
+        let mut kdf_params = [0; 3];
+        for param in kdf_params.iter_mut() {
+            *param = u32::from_le_bytes(self.take(4)?.try_into().unwrap());
+        }
+
+        let kdf = Kdf::from_header(kdf_id, kdf_params).ok_or(HeaderError::UnknownKdf(kdf_id))?;
+        kdf.check_cost().map_err(HeaderError::KdfTooCostly)?;
+
+        Ok(kdf)
+    }
+
+    fn login(&mut self) -> Result<Option<LoginType>, HeaderError> {
+        match self.byte()? {
+            0 => Ok(None),
+            id => Ok(Some(
+                LoginType::from_id(id).ok_or(HeaderError::UnknownLoginType(id))?,
+            )),
+        }
+    }
+}
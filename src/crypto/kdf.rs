@@ -0,0 +1,176 @@
+use argon2::{Algorithm, Argon2, Params, Version};
+use ring::digest::{digest, SHA256};
+
+use super::recipient;
+
+pub const SALT_LEN: usize = 16;
+
+// Defaults for new vaults
+pub const ARGON2ID_MEMORY: u32 = 64 * 1024;
+pub const ARGON2ID_ITERATIONS: u32 = 3;
+pub const ARGON2ID_PARALLELISM: u32 = 4;
+
+pub const SCRYPT_LOG_N: u32 = 17;
+pub const SCRYPT_R: u32 = 8;
+pub const SCRYPT_P: u32 = 1;
+
+// Limits of the parameters, the header isn't authenticated before the key is derived
+// and a forged one must not exhaust the memory or run for hours
+pub const MAX_MEMORY: u32 = 4 * 1024 * 1024;
+pub const MAX_ITERATIONS: u32 = 100;
+pub const MAX_PARALLELISM: u32 = 64;
+pub const MAX_SCRYPT_R: u32 = 32;
+
+/// Password based key derivation used to turn the master secret into the vault key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kdf {
+    /// Unsalted SHA-256, only kept for vaults created by older versions
+    Sha256,
+    /// `memory` is in KiB
+    Argon2id {
+        memory: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+    /// N = 2^`log_n`
+    Scrypt { log_n: u32, r: u32, p: u32 },
+    /// Key slots of recipients: the secret is their identity and the salt
+    /// an ephemeral public key, see `recipient`
+    X25519,
+}
+
+impl Default for Kdf {
+    fn default() -> Self {
+        Kdf::Argon2id {
+            memory: ARGON2ID_MEMORY,
+            iterations: ARGON2ID_ITERATIONS,
+            parallelism: ARGON2ID_PARALLELISM,
+        }
+    }
+}
+
+impl Kdf {
+    /// Identifier stored in the vault header
+    pub fn id(&self) -> u8 {
+        match self {
+            Kdf::Sha256 => 0,
+            Kdf::Argon2id { .. } => 1,
+            Kdf::Scrypt { .. } => 2,
+            Kdf::X25519 => 3,
+        }
+    }
+
+    /// Parameters stored in the vault header
+    pub fn params(&self) -> [u32; 3] {
+        match *self {
+            Kdf::Sha256 | Kdf::X25519 => [0; 3],
+            Kdf::Argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => [memory, iterations, parallelism],
+            Kdf::Scrypt { log_n, r, p } => [log_n, r, p],
+        }
+    }
+
+    pub fn from_header(id: u8, params: [u32; 3]) -> Option<Kdf> {
+        match id {
+            0 => Some(Kdf::Sha256),
+            1 => Some(Kdf::Argon2id {
+                memory: params[0],
+                iterations: params[1],
+                parallelism: params[2],
+            }),
+            2 => Some(Kdf::Scrypt {
+                log_n: params[0],
+                r: params[1],
+                p: params[2],
+            }),
+            3 => Some(Kdf::X25519),
+            _ => None,
+        }
+    }
+
+    /// Memory and time the derivation takes stay within the limits above
+    pub fn check_cost(&self) -> Result<(), String> {
+        match *self {
+            Kdf::Sha256 | Kdf::X25519 => Ok(()),
+            Kdf::Argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => {
+                if memory > MAX_MEMORY {
+                    Err(format!("argon2id memory above {} KiB", MAX_MEMORY))
+                } else if iterations > MAX_ITERATIONS {
+                    Err(format!("argon2id iterations above {}", MAX_ITERATIONS))
+                } else if parallelism > MAX_PARALLELISM {
+                    Err(format!("argon2id parallelism above {}", MAX_PARALLELISM))
+                } else {
+                    Ok(())
+                }
+            }
+            Kdf::Scrypt { log_n, r, p } => {
+                // 128 * r * N bytes, r * N / 8 KiB
+                if log_n > 31 || r > MAX_SCRYPT_R || (r as u64) << log_n >> 3 > MAX_MEMORY as u64 {
+                    Err(format!("scrypt memory above {} KiB", MAX_MEMORY))
+                } else if p > MAX_PARALLELISM {
+                    Err(format!("scrypt parallelism above {}", MAX_PARALLELISM))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kdf::Sha256 => "sha256",
+            Kdf::Argon2id { .. } => "argon2id",
+            Kdf::Scrypt { .. } => "scrypt",
+            Kdf::X25519 => "x25519",
+        }
+    }
+
+    /// Salt length used when creating a new vault with this kdf
+    pub fn salt_len(&self) -> usize {
+        match self {
+            Kdf::Sha256 => 0,
+            _ => SALT_LEN,
+        }
+    }
+
+    pub fn derive_key(&self, secret: &[u8], salt: &[u8]) -> Result<[u8; 32], String> {
+        let mut key = [0; 32];
+
+        match *self {
+            Kdf::Sha256 => {
+                key.copy_from_slice(digest(&SHA256, secret).as_ref());
+            }
+            Kdf::Argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => {
+                let params = Params::new(memory, iterations, parallelism, Some(key.len()))
+                    .map_err(|e| e.to_string())?;
+
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(secret, salt, &mut key)
+                    .map_err(|e| e.to_string())?;
+            }
+            Kdf::Scrypt { log_n, r, p } => {
+                let log_n = u8::try_from(log_n).map_err(|e| e.to_string())?;
+                let params =
+                    scrypt::Params::new(log_n, r, p, key.len()).map_err(|e| e.to_string())?;
+
+                scrypt::scrypt(secret, salt, &params, &mut key).map_err(|e| e.to_string())?;
+            }
+            Kdf::X25519 => {
+                key = recipient::identity_key(secret, salt)?;
+            }
+        }
+
+        Ok(key)
+    }
+}
@@ -0,0 +1,118 @@
+use data_encoding::{BASE64, HEXUPPER_PERMISSIVE};
+use rand::Rng;
+use ring::digest::{digest, SHA256};
+
+/// Create the content of a new keyfile from random bytes
+pub fn generate(size: usize) -> Vec<u8> {
+    let mut content = vec![0; size];
+    rand::thread_rng().fill(&mut content[..]);
+    content
+}
+
+/// Key material of a keyfile.
+///
+/// KeePass XML keyfiles (version 1.0 and 2.0, `.key`/`.keyx`) give the key they contain,
+/// any other file is used as is, whatever its content
+pub fn key_material(content: &[u8]) -> Result<Vec<u8>, String> {
+    let doc = match std::str::from_utf8(content)
+        .ok()
+        .and_then(|r| roxmltree::Document::parse(r.trim_start_matches('\u{feff}')).ok())
+    {
+        Some(r) if r.root_element().has_tag_name("KeyFile") => r,
+        _ => return Ok(content.to_vec()),
+    };
+
+    let version = child_text(doc.root_element(), &["Meta", "Version"])
+        .ok_or("KeePass keyfile without version")?;
+    let data_node = find_child(doc.root_element(), &["Key", "Data"])
+        .ok_or("KeePass keyfile without key data")?;
+    let data: String = data_node
+        .text()
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect();
+
+    if version.starts_with("1.") {
+        BASE64
+            .decode(data.as_bytes())
+            .map_err(|_| "Invalid key data in KeePass keyfile".to_string())
+    } else if version.starts_with("2.") {
+        let key = HEXUPPER_PERMISSIVE
+            .decode(data.as_bytes())
+            .map_err(|_| "Invalid key data in KeePass keyfile")?;
+
+        // The hash attribute is the first 4 bytes of the SHA-256 of the key
+        if let Some(hash) = data_node.attribute("Hash") {
+            let expected = HEXUPPER_PERMISSIVE
+                .decode(hash.as_bytes())
+                .map_err(|_| "Invalid hash in KeePass keyfile")?;
+            if digest(&SHA256, &key).as_ref()[..4] != expected[..] {
+                return Err("KeePass keyfile is corrupted (hash mismatch)".to_string());
+            }
+        }
+
+        Ok(key)
+    } else {
+        Err(format!("Unsupported KeePass keyfile version: {}", version))
+    }
+}
+
+fn find_child<'a, 'input>(
+    node: roxmltree::Node<'a, 'input>,
+    path: &[&str],
+) -> Option<roxmltree::Node<'a, 'input>> {
+    path.iter().try_fold(node, |node, name| {
+        node.children().find(|c| c.has_tag_name(*name))
+    })
+}
+
+fn child_text<'a>(node: roxmltree::Node<'a, '_>, path: &[&str]) -> Option<&'a str> {
+    find_child(node, path)?.text().map(|r| r.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn other_files_are_used_as_is() {
+        let binary = [0xff, 0x00, 0xd8, 0xff, 0xe0];
+        assert_eq!(key_material(&binary).unwrap(), binary);
+
+        let text = b"<html><body>not a keyfile</body></html>";
+        assert_eq!(key_material(text).unwrap(), text);
+    }
+
+    #[test]
+    fn keepass_v2_keyfile() {
+        let keyx = r#"<?xml version="1.0" encoding="utf-8"?>
+<KeyFile>
+    <Meta>
+        <Version>2.0</Version>
+    </Meta>
+    <Key>
+        <Data Hash="630DCD29">
+            00010203 04050607 08090A0B 0C0D0E0F
+            10111213 14151617 18191A1B 1C1D1E1F
+        </Data>
+    </Key>
+</KeyFile>"#;
+
+        let key = key_material(keyx.as_bytes()).unwrap();
+        assert_eq!(key, (0..32).collect::<Vec<u8>>());
+
+        let corrupted = keyx.replace("1C1D1E1F", "1C1D1E1E");
+        assert!(key_material(corrupted.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn keepass_v1_keyfile() {
+        let key = r#"<?xml version="1.0" encoding="utf-8"?>
+<KeyFile>
+    <Meta><Version>1.00</Version></Meta>
+    <Key><Data>AAECAw==</Data></Key>
+</KeyFile>"#;
+
+        assert_eq!(key_material(key.as_bytes()).unwrap(), [0, 1, 2, 3]);
+    }
+}
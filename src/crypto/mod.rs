@@ -1,121 +1,191 @@
-use aes_gcm::{
-    aead::{Aead, KeyInit},
-    Aes256Gcm, Error, Key, Nonce,
-};
-use chacha20::ChaCha20;
+use aes_gcm::Error;
 use rand::Rng;
 use ring::digest::{digest, SHA256};
-use salsa20::{
-    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
-    Salsa20,
-};
-
-use crate::JsonDatabseKMH;
-
-pub fn generate_random_utf8(size: usize) -> Vec<u8> {
-    let rand_string: String = rand::thread_rng()
-        .sample_iter::<char, _>(rand::distributions::Standard)
-        .take(size)
-        .collect();
-    rand_string.as_bytes().to_vec()
-}
-
-pub fn encrypt_database_aes(db: &Vec<JsonDatabseKMH>, password: &String) -> Result<Vec<u8>, Error> {
-    let password_hashed: [u8; 32] = digest(&SHA256, password.as_bytes())
-        .as_ref()
-        .try_into()
-        .unwrap();
-
-    let dbstr = serde_json::to_string(&db).expect("Invalid DB format");
 
-    let aesgcm = Aes256Gcm::new(&Key::<Aes256Gcm>::from_slice(&password_hashed));
-
-    aesgcm.encrypt(Nonce::from_slice(&[0; 12]), dbstr.as_bytes())
+use crate::LoginType;
+
+pub mod cipher;
+pub mod header;
+pub mod kdf;
+pub mod keyfile;
+pub mod password;
+pub mod recipient;
+pub mod slot;
+
+use cipher::VaultCipher;
+use header::VaultHeader;
+use slot::Keyring;
+
+/// What the user unlocks the vault with
+pub enum Credentials {
+    Password(String),
+    /// Key material of the keyfile, see `keyfile::key_material`
+    Keyfile(Vec<u8>),
+    /// Both are needed, like KeePass composite keys
+    Composite(String, Vec<u8>),
+    /// Private key of a recipient, see `kmh share`
+    Identity(recipient::Identity),
 }
 
-pub fn decrypt_database_aes(db: Vec<u8>, password: &String) -> Result<Vec<u8>, Error> {
-    let password_hashed: [u8; 32] = digest(&SHA256, password.as_bytes())
-        .as_ref()
-        .try_into()
-        .unwrap();
-
-    let aesgcm = Aes256Gcm::new(&Key::<Aes256Gcm>::from_slice(&password_hashed));
-
-    aesgcm.decrypt(Nonce::from_slice(&[0; 12]), db.as_slice())
-}
-
-pub fn encrypt_database_salsa20(db: &Vec<JsonDatabseKMH>, password: &String) -> Vec<u8> {
-    let password_hashed: [u8; 32] = digest(&SHA256, password.as_bytes())
-        .as_ref()
-        .try_into()
-        .unwrap();
-
-    let mut db_bytes = serde_json::to_string(&db)
-        .expect("Invalid DB format")
-        .as_bytes()
-        .to_vec();
-
-    let mut salsa20 = Salsa20::new(
-        &password_hashed.try_into().unwrap(),
-        &[0; 8].try_into().unwrap(),
-    );
-
-    salsa20.apply_keystream(&mut db_bytes[..]);
-
-    db_bytes
+impl Credentials {
+    pub fn login_type(&self) -> LoginType {
+        match self {
+            Credentials::Password(_) => LoginType::PASSWORD,
+            Credentials::Keyfile(_) => LoginType::FILE,
+            Credentials::Composite(_, _) => LoginType::COMPOSITE,
+            Credentials::Identity(_) => LoginType::IDENTITY,
+        }
+    }
+
+    /// Input of the kdf
+    pub(super) fn secret(&self) -> Vec<u8> {
+        match self {
+            Credentials::Password(password) => password.as_bytes().to_vec(),
+            Credentials::Keyfile(keyfile) => keyfile.clone(),
+            Credentials::Composite(password, keyfile) => {
+                let mut secret = digest(&SHA256, password.as_bytes()).as_ref().to_vec();
+                secret.extend_from_slice(digest(&SHA256, keyfile).as_ref());
+                secret
+            }
+            Credentials::Identity(identity) => identity.secret_bytes().to_vec(),
+        }
+    }
 }
 
-pub fn decrypt_database_salsa20(mut db: Vec<u8>, password: &String) -> Vec<u8> {
-    let password_hashed: [u8; 32] = digest(&SHA256, password.as_bytes())
-        .as_ref()
-        .try_into()
-        .unwrap();
-
-    let mut salsa20 = Salsa20::new(
-        &password_hashed.try_into().unwrap(),
-        &[0; 8].try_into().unwrap(),
-    );
-
-    salsa20.seek(0u32);
-    salsa20.apply_keystream(&mut db[..]);
-
-    db
+/// Encrypt the serialized database with a fresh random nonce, returns the vault file content
+pub fn encrypt_database(
+    cipher: &'static dyn VaultCipher,
+    db: &[u8],
+    keyring: &Keyring,
+) -> Result<Vec<u8>, Error> {
+    let mut nonce = vec![0; cipher.nonce_size()];
+    rand::thread_rng().fill(&mut nonce[..]);
+
+    let header = VaultHeader {
+        cipher,
+        slots: keyring.slots.clone(),
+        nonce,
+        authenticated: Vec::new(),
+    };
+    // The header is authenticated with the data, a change to the cipher or the slots is detected
+    let mut out = header.to_bytes();
+
+    let key = &keyring.master_key()[..cipher.key_size()];
+    let ciphertext = cipher.encrypt(key, &header.nonce, &out, db)?;
+    out.extend(ciphertext);
+
+    Ok(out)
 }
 
-pub fn encrypt_database_chacha20(db: &Vec<JsonDatabseKMH>, password: &String) -> Vec<u8> {
-    let password_hashed: [u8; 32] = digest(&SHA256, password.as_bytes())
-        .as_ref()
-        .try_into()
-        .unwrap();
-
-    let mut db_bytes = serde_json::to_string(&db)
-        .expect("Invalid DB format")
-        .as_bytes()
-        .to_vec();
-
-    let mut chacha20 = ChaCha20::new(
-        &password_hashed.try_into().unwrap(),
-        &[0; 12].try_into().unwrap(),
-    );
-
-    chacha20.apply_keystream(&mut db_bytes[..]);
-
-    db_bytes
+pub fn decrypt_database(
+    header: &VaultHeader,
+    keyring: &Keyring,
+    ciphertext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let cipher = header.cipher;
+
+    cipher.decrypt(
+        &keyring.master_key()[..cipher.key_size()],
+        &header.nonce,
+        &header.authenticated,
+        ciphertext,
+    )
 }
 
-pub fn decrypt_database_chacha20(mut db: Vec<u8>, password: &String) -> Vec<u8> {
-    let password_hashed: [u8; 32] = digest(&SHA256, password.as_bytes())
-        .as_ref()
-        .try_into()
-        .unwrap();
-
-    let mut chacha20 = ChaCha20::new(
-        &password_hashed.try_into().unwrap(),
-        &[0; 12].try_into().unwrap(),
-    );
-
-    chacha20.seek(0u32);
-    chacha20.apply_keystream(&mut db[..]);
-
-    db
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::JsonDatabseKMH;
+    use kdf::Kdf;
+
+    fn sample_db() -> Vec<u8> {
+        serde_json::to_vec(&[JsonDatabseKMH::new(
+            "title".to_string(),
+            "username".to_string(),
+            "password".to_string(),
+            "notes".to_string(),
+        )])
+        .unwrap()
+    }
+
+    #[test]
+    fn consecutive_saves_use_different_nonces() {
+        let db = sample_db();
+        let key =
+            Keyring::new(&Credentials::Password("password".to_string()), Kdf::Sha256).unwrap();
+
+        for cipher in cipher::available() {
+            let first = encrypt_database(cipher, &db, &key).unwrap();
+            let second = encrypt_database(cipher, &db, &key).unwrap();
+            assert_ne!(first, second, "{}", cipher.name());
+
+            let (first_header, first_ciphertext) = VaultHeader::parse(&first).unwrap();
+            let (second_header, second_ciphertext) = VaultHeader::parse(&second).unwrap();
+            assert_ne!(first_header.nonce, second_header.nonce, "{}", cipher.name());
+            assert_ne!(first_ciphertext, second_ciphertext, "{}", cipher.name());
+        }
+    }
+
+    #[test]
+    fn nonce_is_read_back_from_header() {
+        let db = sample_db();
+        let key =
+            Keyring::new(&Credentials::Password("password".to_string()), Kdf::Sha256).unwrap();
+
+        for cipher in cipher::available() {
+            let vault = encrypt_database(cipher, &db, &key).unwrap();
+            let (header, ciphertext) = VaultHeader::parse(&vault).unwrap();
+            assert_eq!(header.cipher.id(), cipher.id());
+
+            let decrypted = decrypt_database(&header, &key, ciphertext).unwrap();
+            assert_eq!(decrypted, db, "{}", cipher.name());
+        }
+    }
+
+    #[test]
+    fn wrong_key_or_tampering_is_rejected() {
+        let db = sample_db();
+        let key =
+            Keyring::new(&Credentials::Password("password".to_string()), Kdf::Sha256).unwrap();
+        let wrong_key =
+            Keyring::new(&Credentials::Password("wrong".to_string()), Kdf::Sha256).unwrap();
+
+        for cipher in cipher::available() {
+            let vault = encrypt_database(cipher, &db, &key).unwrap();
+            let (header, ciphertext) = VaultHeader::parse(&vault).unwrap();
+            assert!(decrypt_database(&header, &wrong_key, ciphertext).is_err());
+
+            let mut tampered = ciphertext.to_vec();
+            tampered[0] ^= 1;
+            assert!(decrypt_database(&header, &key, &tampered).is_err());
+
+            // The header is authenticated too, here the kdf of the first slot
+            let mut tampered = vault.clone();
+            tampered[header::MAGIC.len() + 4] ^= 1;
+            let (header, ciphertext) = VaultHeader::parse(&tampered).unwrap();
+            assert!(decrypt_database(&header, &key, ciphertext).is_err());
+        }
+    }
+
+    #[test]
+    fn costly_kdf_in_header_is_rejected() {
+        let kdf = Kdf::Argon2id {
+            memory: 8,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let key = Keyring::new(&Credentials::Password("password".to_string()), kdf).unwrap();
+        let vault =
+            encrypt_database(cipher::available().next().unwrap(), &sample_db(), &key).unwrap();
+        assert!(VaultHeader::parse(&vault).is_ok());
+
+        // Memory of the first slot, right after its kdf id
+        let mut forged = vault.clone();
+        let memory = header::MAGIC.len() + 5;
+        forged[memory..memory + 4].copy_from_slice(&0xFFFF_FFF0u32.to_le_bytes());
+        assert!(matches!(
+            VaultHeader::parse(&forged),
+            Err(header::HeaderError::KdfTooCostly(_))
+        ));
+    }
 }
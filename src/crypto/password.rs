@@ -0,0 +1,13 @@
+use rand::Rng;
+
+const CHARSET: &[u8] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789!#$%&()*+,-./:;<=>?@[]^_{|}~";
+
+/// Random password for an entry
+pub fn generate(length: usize) -> String {
+    let mut rng = rand::thread_rng();
+
+    (0..length)
+        .map(|_| CHARSET[rng.gen_range(0..CHARSET.len())] as char)
+        .collect()
+}
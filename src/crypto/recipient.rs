@@ -0,0 +1,174 @@
+use data_encoding::BASE32_NOPAD;
+use rand::rngs::OsRng;
+use ring::hkdf;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+const PUBLIC_PREFIX: &str = "kmhpub1";
+const SECRET_PREFIX: &str = "KMH-SECRET-KEY-1";
+const HKDF_INFO: &[u8] = b"kmh x25519 key slot";
+const E_LOW_ORDER_KEY: &str = "Invalid public key: it gives the same shared secret to everyone";
+
+/// Private key of a recipient, read from the file created by `kmh keygen`
+pub struct Identity {
+    secret: StaticSecret,
+}
+
+impl Identity {
+    pub fn generate() -> Identity {
+        Identity {
+            secret: StaticSecret::random_from_rng(OsRng),
+        }
+    }
+
+    /// Parse an identity file, lines starting with `#` are comments
+    pub fn parse(content: &str) -> Result<Identity, String> {
+        let line = content
+            .lines()
+            .map(|r| r.trim())
+            .find(|r| r.starts_with(SECRET_PREFIX))
+            .ok_or("No secret key found in the identity file")?;
+
+        let secret: [u8; 32] = BASE32_NOPAD
+            .decode(&line.as_bytes()[SECRET_PREFIX.len()..])
+            .ok()
+            .and_then(|r| r.try_into().ok())
+            .ok_or("Invalid secret key in the identity file")?;
+
+        Ok(Identity {
+            secret: StaticSecret::from(secret),
+        })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey::from(&self.secret)
+    }
+
+    pub fn secret_bytes(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+
+    /// Content of the identity file
+    pub fn to_file(&self) -> String {
+        format!(
+            "# public key: {}\n{}{}\n",
+            encode_public(&self.public_key()),
+            SECRET_PREFIX,
+            BASE32_NOPAD.encode(&self.secret.to_bytes())
+        )
+    }
+}
+
+pub fn encode_public(key: &PublicKey) -> String {
+    format!(
+        "{}{}",
+        PUBLIC_PREFIX,
+        BASE32_NOPAD.encode(key.as_bytes()).to_lowercase()
+    )
+}
+
+pub fn parse_public(key: &str) -> Result<PublicKey, String> {
+    let key = key.trim();
+    let invalid = || format!("Invalid public key: {}", key);
+
+    let encoded = key.strip_prefix(PUBLIC_PREFIX).ok_or_else(invalid)?;
+    let bytes: [u8; 32] = BASE32_NOPAD
+        .decode(encoded.to_uppercase().as_bytes())
+        .ok()
+        .and_then(|r| r.try_into().ok())
+        .ok_or_else(invalid)?;
+
+    // Checked again when the slot is created, but fail before the vault is unlocked
+    let key = PublicKey::from(bytes);
+    if !EphemeralSecret::random_from_rng(OsRng)
+        .diffie_hellman(&key)
+        .was_contributory()
+    {
+        return Err(E_LOW_ORDER_KEY.to_string());
+    }
+    Ok(key)
+}
+
+/// Key wrapping the master key for `recipient`, returns the ephemeral public key
+/// to store in the slot and the key.
+///
+/// Low-order keys are rejected like in `age`: the shared secret would be all zeros,
+/// and anyone holding the vault could compute the key
+pub fn recipient_key(recipient: &PublicKey) -> Result<([u8; 32], [u8; 32]), String> {
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(recipient);
+    if !shared.was_contributory() {
+        return Err(E_LOW_ORDER_KEY.to_string());
+    }
+
+    let key = wrapping_key(shared.as_bytes(), ephemeral_public.as_bytes(), recipient);
+
+    Ok((ephemeral_public.to_bytes(), key))
+}
+
+/// Same key as `recipient_key`, computed from the identity and the ephemeral public key
+pub fn identity_key(secret: &[u8], ephemeral_public: &[u8]) -> Result<[u8; 32], String> {
+    let secret: [u8; 32] = secret.try_into().map_err(|_| "Invalid identity")?;
+    let ephemeral_public: [u8; 32] = ephemeral_public
+        .try_into()
+        .map_err(|_| "Invalid ephemeral public key")?;
+
+    let identity = Identity {
+        secret: StaticSecret::from(secret),
+    };
+    let shared = identity
+        .secret
+        .diffie_hellman(&PublicKey::from(ephemeral_public));
+    if !shared.was_contributory() {
+        return Err("Invalid ephemeral public key: low order point".to_string());
+    }
+
+    Ok(wrapping_key(
+        shared.as_bytes(),
+        &ephemeral_public,
+        &identity.public_key(),
+    ))
+}
+
+/// HKDF-SHA256 of the shared secret, salted with both public keys like `age`
+fn wrapping_key(shared: &[u8; 32], ephemeral_public: &[u8], recipient: &PublicKey) -> [u8; 32] {
+    let mut salt = ephemeral_public.to_vec();
+    salt.extend_from_slice(recipient.as_bytes());
+
+    let mut key = [0; 32];
+    hkdf::Salt::new(hkdf::HKDF_SHA256, &salt)
+        .extract(shared)
+        .expand(&[HKDF_INFO], hkdf::HKDF_SHA256)
+        .and_then(|r| r.fill(&mut key))
+        .expect("HKDF output length");
+
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_file_and_public_key_round_trip() {
+        let identity = Identity::generate();
+        let public = encode_public(&identity.public_key());
+
+        let parsed = Identity::parse(&identity.to_file()).unwrap();
+        assert_eq!(parsed.secret_bytes(), identity.secret_bytes());
+        assert_eq!(parse_public(&public).unwrap(), identity.public_key());
+
+        assert!(parse_public("kmhpub1abc").is_err());
+        assert!(Identity::parse("# public key only").is_err());
+    }
+
+    #[test]
+    fn low_order_keys_are_rejected() {
+        let zero = PublicKey::from([0; 32]);
+        assert!(parse_public(&encode_public(&zero)).is_err());
+        assert!(recipient_key(&zero).is_err());
+
+        let identity = Identity::generate();
+        assert!(identity_key(&identity.secret_bytes(), &[0; 32]).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use rand::Rng;
+
+use super::{
+    cipher::{self, VaultCipher},
+    header::MAX_SLOTS,
+    kdf::Kdf,
+    recipient, Credentials,
+};
+use crate::LoginType;
+use x25519_dalek::PublicKey;
+
+/// Cipher wrapping the master key in a slot, independent from the vault cipher
+/// so the vault can switch cipher without touching the slots
+pub static WRAP_CIPHER: &dyn VaultCipher = &cipher::XCHACHA20POLY1305;
+
+/// One way to unlock a vault
+#[derive(Clone)]
+pub struct KeySlot {
+    /// None for vaults that don't record it
+    pub login: Option<LoginType>,
+    pub kdf: Kdf,
+    pub salt: Vec<u8>,
+    /// None for vaults written before key slots, the derived key is the master key
+    pub wrapped_key: Option<WrappedKey>,
+}
+
+/// Master key encrypted with the key derived from the slot credentials
+#[derive(Clone)]
+pub struct WrappedKey {
+    pub nonce: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+impl KeySlot {
+    fn derive_key(&self, credentials: &Credentials) -> Result<[u8; 32], String> {
+        self.kdf.derive_key(&credentials.secret(), &self.salt)
+    }
+
+    pub fn matches(&self, login: LoginType) -> bool {
+        self.login.is_none_or(|l| l == login)
+    }
+}
+
+/// Unlocked vault key and the slots it is wrapped in
+pub struct Keyring {
+    pub slots: Vec<KeySlot>,
+    master: [u8; 32],
+}
+
+impl Keyring {
+    /// New random master key with a single slot for `credentials`
+    pub fn new(credentials: &Credentials, kdf: Kdf) -> Result<Keyring, String> {
+        let mut keyring = Keyring::random();
+        keyring.add_slot(credentials, kdf)?;
+
+        Ok(keyring)
+    }
+
+    /// New random master key without any slot, add one before saving
+    pub fn random() -> Keyring {
+        let mut master = [0; 32];
+        rand::thread_rng().fill(&mut master[..]);
+
+        Keyring {
+            slots: Vec::new(),
+            master,
+        }
+    }
+
+    /// Try every slot matching the credentials, returns the keyring and the index of the slot
+    /// that opened it, or None if no slot accepts them.
+    ///
+    /// Slots of old vaults can't be checked, they always "open" and a wrong key
+    /// only shows when decrypting the database
+    pub fn unlock(
+        slots: Vec<KeySlot>,
+        credentials: &Credentials,
+    ) -> Result<Option<(Keyring, usize)>, String> {
+        let login = credentials.login_type();
+        let mut master = None;
+
+        for (i, slot) in slots.iter().enumerate() {
+            if !slot.matches(login) {
+                continue;
+            }
+
+            let slot_key = slot.derive_key(credentials)?;
+            let key = match &slot.wrapped_key {
+                None => Some(slot_key),
+                Some(wrapped) => WRAP_CIPHER
+                    .decrypt(&slot_key, &wrapped.nonce, &[], &wrapped.key)
+                    .ok()
+                    .and_then(|r| r.try_into().ok()),
+            };
+
+            if let Some(key) = key {
+                master = Some((key, i));
+                break;
+            }
+        }
+
+        Ok(master.map(|(master, i)| (Keyring { slots, master }, i)))
+    }
+
+    pub fn master_key(&self) -> &[u8; 32] {
+        &self.master
+    }
+
+    /// Whether the vault still uses the format without key slots
+    pub fn is_legacy(&self) -> bool {
+        self.slots.iter().any(|s| s.wrapped_key.is_none())
+    }
+
+    pub fn add_slot(&mut self, credentials: &Credentials, kdf: Kdf) -> Result<(), String> {
+        self.check_slot_count()?;
+        let slot = self.new_slot(credentials, kdf)?;
+        self.slots.push(slot);
+        Ok(())
+    }
+
+    pub fn replace_slot(
+        &mut self,
+        index: usize,
+        credentials: &Credentials,
+        kdf: Kdf,
+    ) -> Result<(), String> {
+        self.slots[index] = self.new_slot(credentials, kdf)?;
+        Ok(())
+    }
+
+    /// Slot opened by the identity of `recipient`, no secret of theirs is needed
+    pub fn add_recipient(&mut self, recipient: &PublicKey) -> Result<(), String> {
+        self.check_slot_count()?;
+        let (ephemeral_public, slot_key) = recipient::recipient_key(recipient)?;
+
+        self.slots.push(KeySlot {
+            login: Some(LoginType::IDENTITY),
+            kdf: Kdf::X25519,
+            salt: ephemeral_public.to_vec(),
+            wrapped_key: Some(self.wrap(&slot_key)?),
+        });
+        Ok(())
+    }
+
+    /// Room for one more slot in the header
+    pub fn check_slot_count(&self) -> Result<(), String> {
+        if self.slots.len() >= MAX_SLOTS {
+            return Err(format!(
+                "A vault can't have more than {} key slots",
+                MAX_SLOTS
+            ));
+        }
+        Ok(())
+    }
+
+    fn new_slot(&self, credentials: &Credentials, kdf: Kdf) -> Result<KeySlot, String> {
+        let mut salt = vec![0; kdf.salt_len()];
+        rand::thread_rng().fill(&mut salt[..]);
+
+        let mut slot = KeySlot {
+            login: Some(credentials.login_type()),
+            kdf,
+            salt,
+            wrapped_key: None,
+        };
+
+        let slot_key = slot.derive_key(credentials)?;
+        slot.wrapped_key = Some(self.wrap(&slot_key)?);
+
+        Ok(slot)
+    }
+
+    fn wrap(&self, slot_key: &[u8; 32]) -> Result<WrappedKey, String> {
+        let mut nonce = vec![0; WRAP_CIPHER.nonce_size()];
+        rand::thread_rng().fill(&mut nonce[..]);
+
+        let key = WRAP_CIPHER
+            .encrypt(slot_key, &nonce, &[], &self.master)
+            .map_err(|_| "Key wrapping failed")?;
+
+        Ok(WrappedKey { nonce, key })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::header::VaultHeader;
+
+    #[test]
+    fn every_slot_unlocks_the_same_master_key() {
+        let password = Credentials::Password("password".to_string());
+        let keyfile = Credentials::Keyfile(vec![1, 2, 3]);
+        let other = Credentials::Password("other".to_string());
+
+        let mut keyring = Keyring::new(&password, Kdf::Sha256).unwrap();
+        keyring.add_slot(&keyfile, Kdf::Sha256).unwrap();
+        keyring.add_slot(&other, Kdf::Sha256).unwrap();
+
+        // Through the header, like a saved vault
+        let header = VaultHeader {
+            cipher: &cipher::AES256GCM,
+            slots: keyring.slots.clone(),
+            nonce: vec![0; cipher::AES256GCM.nonce_size()],
+            authenticated: Vec::new(),
+        };
+        let (header, _) = VaultHeader::parse(&header.to_bytes()).unwrap();
+
+        for (credentials, index) in [(&password, 0), (&keyfile, 1), (&other, 2)] {
+            let (unlocked, slot) = Keyring::unlock(header.slots.clone(), credentials)
+                .unwrap()
+                .unwrap();
+            assert_eq!(slot, index);
+            assert_eq!(unlocked.master_key(), keyring.master_key());
+        }
+
+        let wrong = Credentials::Password("wrong".to_string());
+        assert!(Keyring::unlock(header.slots, &wrong).unwrap().is_none());
+    }
+
+    #[test]
+    fn recipient_slot_is_opened_by_its_identity() {
+        let identity = recipient::Identity::generate();
+        let other = recipient::Identity::generate();
+
+        let mut keyring = Keyring::random();
+        keyring.add_recipient(&identity.public_key()).unwrap();
+
+        let (unlocked, _) =
+            Keyring::unlock(keyring.slots.clone(), &Credentials::Identity(identity))
+                .unwrap()
+                .unwrap();
+        assert_eq!(unlocked.master_key(), keyring.master_key());
+
+        let wrong = Keyring::unlock(keyring.slots, &Credentials::Identity(other)).unwrap();
+        assert!(wrong.is_none());
+    }
+
+    #[test]
+    fn slot_count_fits_in_the_header() {
+        let public_key = recipient::Identity::generate().public_key();
+
+        let mut keyring = Keyring::random();
+        for _ in 0..MAX_SLOTS {
+            keyring.add_recipient(&public_key).unwrap();
+        }
+        assert!(keyring.add_recipient(&public_key).is_err());
+
+        let header = VaultHeader {
+            cipher: &cipher::AES256GCM,
+            slots: keyring.slots.clone(),
+            nonce: vec![0; cipher::AES256GCM.nonce_size()],
+            authenticated: Vec::new(),
+        };
+        let (header, _) = VaultHeader::parse(&header.to_bytes()).unwrap();
+        assert_eq!(header.slots.len(), MAX_SLOTS);
+    }
+}
@@ -0,0 +1,182 @@
+use std::{fmt, io};
+
+use colored::Colorize;
+use serde_json::json;
+
+use crate::crypto::header::HeaderError;
+
+/// How errors are written on stderr, see `--error-format`
+#[derive(Clone, Copy)]
+pub enum ErrorFormat {
+    Text,
+    Json,
+}
+
+impl ErrorFormat {
+    pub fn from_name(name: &str) -> Option<ErrorFormat> {
+        match name.to_lowercase().as_str() {
+            "text" => Some(ErrorFormat::Text),
+            "json" => Some(ErrorFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Everything that can make a command fail, each category ends the process with its own code
+#[derive(Debug)]
+pub enum KmhError {
+    /// A file, or the terminal, couldn't be read or written
+    Io(String, io::Error),
+    /// The credentials don't open the vault
+    WrongKey,
+    /// The vault can't be read, even with the right credentials
+    CorruptVault(String),
+    /// Unknown option value, invalid ID...
+    BadInput(String),
+    Clipboard(String),
+    Export(String),
+    /// No entry matches the search
+    NotFound(String),
+    /// Several entries match the search, with their ID and title
+    Ambiguous(String, Vec<(usize, String)>),
+    /// A prompt was left with Esc or Ctrl + c
+    Cancelled,
+}
+
+impl KmhError {
+    /// For `map_err`, keeps the path in the message
+    pub fn io(path: &str) -> impl FnOnce(io::Error) -> KmhError + '_ {
+        move |e| KmhError::Io(path.to_string(), e)
+    }
+
+    /// Exit code of the process, documented in the README and never reused for
+    /// another category. 2 is also what clap uses for invalid arguments
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            KmhError::BadInput(_) => 2,
+            KmhError::Io(_, _) => 3,
+            KmhError::WrongKey => 4,
+            KmhError::CorruptVault(_) => 5,
+            KmhError::Clipboard(_) => 6,
+            KmhError::Export(_) => 7,
+            KmhError::NotFound(_) => 8,
+            KmhError::Ambiguous(_, _) => 9,
+            KmhError::Cancelled => 130,
+        }
+    }
+
+    /// Stable name of the category, the `error` field of the json output
+    pub fn kind(&self) -> &'static str {
+        match self {
+            KmhError::Io(_, _) => "io",
+            KmhError::WrongKey => "wrong_key",
+            KmhError::CorruptVault(_) => "corrupt_vault",
+            KmhError::BadInput(_) => "bad_input",
+            KmhError::Clipboard(_) => "clipboard",
+            KmhError::Export(_) => "export",
+            KmhError::NotFound(_) => "not_found",
+            KmhError::Ambiguous(_, _) => "ambiguous",
+            KmhError::Cancelled => "cancelled",
+        }
+    }
+
+    /// Print the error on stderr
+    pub fn report(&self, format: ErrorFormat) {
+        match format {
+            ErrorFormat::Text => eprintln!("{} {}", "Error:".bright_red().bold(), self),
+            ErrorFormat::Json => eprintln!("{}", self.to_json()),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut out = json!({
+            "error": self.kind(),
+            "code": self.exit_code(),
+            "message": self.to_string(),
+        });
+
+        if let KmhError::Io(path, e) = self {
+            out["path"] = json!(path);
+            out["io_error"] = json!(format!("{:?}", e.kind()));
+        }
+        if let KmhError::Ambiguous(_, candidates) = self {
+            out["candidates"] = candidates
+                .iter()
+                .map(|(id, title)| json!({ "id": id, "title": title }))
+                .collect();
+        }
+
+        out
+    }
+}
+
+impl fmt::Display for KmhError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KmhError::Io(path, e) => write!(f, "{}: {}", path, e),
+            KmhError::WrongKey => write!(f, "Wrong password, keyfile or identity"),
+            KmhError::CorruptVault(e) => write!(f, "Corrupted vault: {}", e),
+            KmhError::BadInput(e) => write!(f, "{}", e),
+            KmhError::Clipboard(e) => write!(f, "Clipboard: {}", e),
+            KmhError::Export(e) => write!(f, "Export failed: {}", e),
+            KmhError::NotFound(query) => write!(f, "No entry matches `{}`", query),
+            KmhError::Ambiguous(query, candidates) => {
+                let candidates: Vec<String> = candidates
+                    .iter()
+                    .map(|(id, title)| format!("{} ({})", id, title))
+                    .collect();
+                write!(
+                    f,
+                    "`{}` matches several entries: {}, use the ID",
+                    query,
+                    candidates.join(", ")
+                )
+            }
+            KmhError::Cancelled => write!(f, "Cancelled"),
+        }
+    }
+}
+
+impl From<HeaderError> for KmhError {
+    fn from(e: HeaderError) -> Self {
+        KmhError::CorruptVault(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes_are_stable() {
+        let errors = [
+            (KmhError::BadInput(String::new()), 2),
+            (
+                KmhError::Io(String::new(), io::ErrorKind::NotFound.into()),
+                3,
+            ),
+            (KmhError::WrongKey, 4),
+            (KmhError::CorruptVault(String::new()), 5),
+            (KmhError::Clipboard(String::new()), 6),
+            (KmhError::Export(String::new()), 7),
+            (KmhError::NotFound(String::new()), 8),
+            (KmhError::Ambiguous(String::new(), Vec::new()), 9),
+            (KmhError::Cancelled, 130),
+        ];
+
+        for (error, code) in errors {
+            assert_eq!(error.exit_code(), code, "{}", error.kind());
+        }
+    }
+
+    #[test]
+    fn json_output() {
+        let error = KmhError::Io("vault.kmh".to_string(), io::ErrorKind::NotFound.into());
+        let out = error.to_json();
+
+        assert_eq!(out["error"], "io");
+        assert_eq!(out["code"], 3);
+        assert_eq!(out["path"], "vault.kmh");
+        assert_eq!(out["io_error"], "NotFound");
+    }
+}
@@ -1,12 +1,73 @@
-use std::fs;
+use std::{fmt::Display, fs};
 
-use crate::DBManage;
+use crate::{error::KmhError, format_date, DBManage};
 
-pub fn csv_export(dbmanage: &DBManage, exportfilename: &String) {
-    let hfile = fs::File::create(exportfilename).unwrap();
+const CSV_HEADERS: [&str; 11] = [
+    "uuid",
+    "group",
+    "title",
+    "username",
+    "password",
+    "notes",
+    "urls",
+    "tags",
+    "created_at",
+    "modified_at",
+    "password_changed_at",
+];
+
+pub fn csv_export(dbmanage: &DBManage, exportfilename: &str) -> Result<(), KmhError> {
+    let hfile = fs::File::create(exportfilename).map_err(export_error(exportfilename))?;
+
+    // One column per custom field name, in the order they first appear
+    let mut custom: Vec<&str> = Vec::new();
+    for field in dbmanage.db.iter().flat_map(|r| &r.fields) {
+        if !custom.contains(&field.name.as_str()) {
+            custom.push(&field.name);
+        }
+    }
 
     let mut wtr = csv::Writer::from_writer(hfile);
-    for (i, record) in dbmanage.db.iter().enumerate(){
-        wtr.write_record(vec![&i.to_string(), &record.title, &record.username, &record.password, &record.notes]).expect("Ops... (*ﾟ∀ﾟ)っ［.+:｡☆Good Night☆.+:｡］");
+    wtr.write_record(CSV_HEADERS.iter().chain(&custom))
+        .map_err(export_error(exportfilename))?;
+
+    for record in &dbmanage.db {
+        let [created_at, modified_at, password_changed_at] = [
+            record.created_at,
+            record.modified_at,
+            record.password_changed_at,
+        ]
+        .map(|r| r.map(format_date).unwrap_or_default());
+
+        let mut row = vec![
+            record.id.to_string(),
+            record.group.clone(),
+            record.title.clone(),
+            record.username.clone(),
+            record.password.clone(),
+            record.notes.clone(),
+            record.urls.join(" "),
+            record.tags.join(","),
+            created_at,
+            modified_at,
+            password_changed_at,
+        ];
+        row.extend(custom.iter().map(|name| {
+            record
+                .fields
+                .iter()
+                .find(|r| r.name == *name)
+                .map(|r| r.value.clone())
+                .unwrap_or_default()
+        }));
+
+        wtr.write_record(row)
+            .map_err(export_error(exportfilename))?;
     }
-}
\ No newline at end of file
+
+    wtr.flush().map_err(export_error(exportfilename))
+}
+
+fn export_error<E: Display>(exportfilename: &str) -> impl FnOnce(E) -> KmhError + '_ {
+    move |e| KmhError::Export(format!("{}: {}", exportfilename, e))
+}
@@ -0,0 +1,150 @@
+use serde::{Deserialize, Serialize};
+
+/// Named value added to an entry, besides title, username, password and notes
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct CustomField {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: FieldType,
+    pub value: String,
+    /// Masked like the password
+    #[serde(default)]
+    pub protected: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldType {
+    Text,
+    /// Always protected
+    Hidden,
+    Url,
+    Email,
+    Number,
+}
+
+impl FieldType {
+    pub const ALL: [FieldType; 5] = [
+        FieldType::Text,
+        FieldType::Hidden,
+        FieldType::Url,
+        FieldType::Email,
+        FieldType::Number,
+    ];
+
+    pub fn from_name(name: &str) -> Option<FieldType> {
+        FieldType::ALL
+            .into_iter()
+            .find(|r| r.name() == name.to_lowercase())
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            FieldType::Text => "text",
+            FieldType::Hidden => "hidden",
+            FieldType::Url => "url",
+            FieldType::Email => "email",
+            FieldType::Number => "number",
+        }
+    }
+
+    /// Check the value looks like this type
+    pub fn check(&self, value: &str) -> Result<(), String> {
+        let valid = match self {
+            FieldType::Text | FieldType::Hidden => true,
+            // Like the URLs of the entries, https is assumed without a scheme
+            FieldType::Url => crate::urls::parse(value).is_ok(),
+            FieldType::Email => value
+                .split_once('@')
+                .is_some_and(|(user, domain)| !user.is_empty() && domain.contains('.')),
+            FieldType::Number => value.trim().parse::<f64>().is_ok(),
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(format!("`{}` isn't a valid {}", value, self.name()))
+        }
+    }
+}
+
+impl CustomField {
+    pub fn new(
+        name: String,
+        kind: FieldType,
+        value: String,
+        protected: bool,
+    ) -> Result<Self, String> {
+        if name.trim().is_empty() {
+            return Err("A custom field needs a name".to_string());
+        }
+        kind.check(&value)?;
+
+        Ok(CustomField {
+            name,
+            kind,
+            value,
+            protected: protected || kind == FieldType::Hidden,
+        })
+    }
+
+    /// Parse `[!][type:]name=value` from the command line, the type is text by default
+    /// and `!` protects the field
+    pub fn parse(arg: &str) -> Result<Self, String> {
+        let (protected, field) = match arg.strip_prefix('!') {
+            Some(r) => (true, r),
+            None => (false, arg),
+        };
+        let (name, value) = field
+            .split_once('=')
+            .ok_or_else(|| format!("`{}` isn't [type:]name=value", arg))?;
+
+        // A name can contain `:` as long as what's before isn't a type
+        let (kind, name) = name
+            .split_once(':')
+            .and_then(|(kind, rest)| Some((FieldType::from_name(kind)?, rest)))
+            .unwrap_or((FieldType::Text, name));
+
+        CustomField::new(name.to_string(), kind, value.to_string(), protected)
+    }
+}
+
+/// Add `field`, or replace the one with the same name (case insensitive)
+pub fn set(fields: &mut Vec<CustomField>, field: CustomField) {
+    match fields
+        .iter_mut()
+        .find(|r| r.name.to_lowercase() == field.name.to_lowercase())
+    {
+        Some(r) => *r = field,
+        None => fields.push(field),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_check_types() {
+        let field = CustomField::parse("hidden:Recovery code=abcd=").unwrap();
+        assert_eq!(field.name, "Recovery code");
+        assert_eq!(field.value, "abcd=");
+        assert!(field.kind == FieldType::Hidden && field.protected);
+
+        let field = CustomField::parse("Security question: pet=cat").unwrap();
+        assert!(field.kind == FieldType::Text && !field.protected);
+        assert_eq!(field.name, "Security question: pet");
+
+        assert!(CustomField::parse("number:Account=12a").is_err());
+        assert!(CustomField::parse("email:Backup=alice@example.org").is_ok());
+        assert!(CustomField::parse("email:Backup=alice").is_err());
+        assert!(CustomField::parse("url:Console=https://aws.amazon.com").is_ok());
+        assert!(CustomField::parse("url:Console=aws.amazon.com").is_ok());
+        assert!(CustomField::parse("url:Console=not a url").is_err());
+
+        let field = CustomField::parse("!number:Account=123").unwrap();
+        assert!(field.kind == FieldType::Number && field.protected);
+        assert_eq!(field.name, "Account");
+        assert!(CustomField::parse("no value").is_err());
+    }
+}
@@ -0,0 +1,90 @@
+/// Path of a group, like `Work/AWS/prod`, the empty path is the root of the vault.
+/// `Work/AWS` for ` /Work//AWS/ `, segments are trimmed and empty ones dropped
+pub fn normalize(path: &str) -> String {
+    path.split('/')
+        .map(str::trim)
+        .filter(|r| !r.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// `path` is `group` or one of its subgroups
+pub fn contains(group: &str, path: &str) -> bool {
+    group.is_empty()
+        || path == group
+        || path.strip_prefix(group).is_some_and(|r| r.starts_with('/'))
+}
+
+/// Subgroup of `group` directly under it that `path` is in, `AWS` for `Work` and `Work/AWS/prod`
+pub fn child<'a>(group: &str, path: &'a str) -> Option<&'a str> {
+    if path == group || !contains(group, path) {
+        return None;
+    }
+
+    let rest = if group.is_empty() {
+        path
+    } else {
+        &path[group.len() + 1..]
+    };
+    rest.split('/').next()
+}
+
+/// `Work` for `Work/AWS`, the root for a group at the top
+pub fn parent(group: &str) -> &str {
+    group.rsplit_once('/').map(|(r, _)| r).unwrap_or("")
+}
+
+/// Path to show to the user, `/` for the root
+pub fn display(group: &str) -> &str {
+    if group.is_empty() {
+        "/"
+    } else {
+        group
+    }
+}
+
+/// Tag typed by a user, trimmed. Commas separate the tags in the menu and the CSV
+pub fn tag(tag: &str) -> Result<String, String> {
+    let tag = tag.trim();
+    if tag.is_empty() {
+        return Err("A tag can't be empty".to_string());
+    }
+    if tag.contains(',') {
+        return Err(format!("`{}`: a tag can't contain a comma", tag));
+    }
+    Ok(tag.to_string())
+}
+
+/// Tags are compared ignoring case
+pub fn has_tag(tags: &[String], tag: &str) -> bool {
+    tags.iter().any(|r| r.to_lowercase() == tag.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_paths() {
+        assert_eq!(normalize(" /Work//AWS/ prod /"), "Work/AWS/prod");
+        assert_eq!(normalize("/"), "");
+
+        assert!(contains("", "Work"));
+        assert!(contains("Work", "Work/AWS/prod"));
+        assert!(contains("Work", "Work"));
+        assert!(!contains("Work", "Workshop"));
+        assert!(!contains("Work/AWS", "Work"));
+
+        assert_eq!(child("", "Work/AWS"), Some("Work"));
+        assert_eq!(child("Work", "Work/AWS/prod"), Some("AWS"));
+        assert_eq!(child("Work", "Work"), None);
+        assert_eq!(child("Work", "Home/AWS"), None);
+
+        assert_eq!(parent("Work/AWS"), "Work");
+        assert_eq!(parent("Work"), "");
+
+        assert_eq!(tag(" prod ").unwrap(), "prod");
+        assert!(tag("a,b").is_err() && tag(" ").is_err());
+        assert!(has_tag(&["Prod".to_string()], "prod"));
+    }
+}
@@ -1,15 +1,18 @@
 use colored::Colorize;
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
 use inquire::{
     ui::{Color, RenderConfig, StyleSheet, Styled},
-    Password, PasswordDisplayMode, Select, Text,
+    InquireError, Password, PasswordDisplayMode, Select, Text,
 };
 
+use crate::error::KmhError;
+
 #[cfg(target_os = "windows")]
 pub fn clear_screen() {
     std::process::Command::new("cmd")
         .args(&["/C", "cls"])
         .status()
-        .expect("Failed to clear screen");
+        .ok();
 }
 
 #[cfg(not(target_os = "windows"))]
@@ -18,31 +21,78 @@ pub fn clear_screen() {
 }
 
 fn themecfg() -> RenderConfig {
-    let mut default_cfg = RenderConfig::default();
+    RenderConfig {
+        selected_option: Some(StyleSheet::default().with_fg(Color::LightGreen)),
+        answer: StyleSheet::default().with_fg(Color::LightCyan),
+        highlighted_option_prefix: Styled::new("➤").with_fg(Color::LightCyan),
+        answered_prompt_prefix: Styled::new("➤").with_fg(Color::LightGreen),
+        ..RenderConfig::default()
+    }
+}
+
+fn prompt_error(e: InquireError) -> KmhError {
+    match e {
+        InquireError::OperationCanceled | InquireError::OperationInterrupted => KmhError::Cancelled,
+        InquireError::IO(e) => KmhError::Io("terminal".to_string(), e),
+        InquireError::NotTTY => KmhError::Io(
+            "terminal".to_string(),
+            std::io::Error::other("the input device is not a TTY"),
+        ),
+        e => KmhError::BadInput(e.to_string()),
+    }
+}
 
-    default_cfg.selected_option = Some(StyleSheet::default().with_fg(Color::LightGreen));
-    default_cfg.answer = StyleSheet::default().with_fg(Color::LightCyan);
-    default_cfg.highlighted_option_prefix = Styled::new("➤").with_fg(Color::LightCyan);
-    default_cfg.answered_prompt_prefix = Styled::new("➤").with_fg(Color::LightGreen);
+pub fn select(options: Vec<&str>, message: &str) -> Result<String, KmhError> {
+    let mut select_mode = Select::new(message, options);
+    select_mode.vim_mode = false;
+    select_mode.help_message = None;
+    select_mode.render_config = themecfg();
 
-    default_cfg
+    match select_mode.prompt() {
+        Ok(ans) => Ok(ans.to_string().to_lowercase()),
+        Err(e) => Err(prompt_error(e)),
+    }
 }
 
-pub fn select(options: Vec<&str>, message: &str) -> Option<String> {
+/// Like `select` for options typed by the user, like group names, whose case matters.
+/// Returns the index of the chosen one
+pub fn select_index(options: Vec<String>, message: &str) -> Result<usize, KmhError> {
     let mut select_mode = Select::new(message, options);
     select_mode.vim_mode = false;
     select_mode.help_message = None;
     select_mode.render_config = themecfg();
 
-    let ans = match select_mode.prompt() {
-        Ok(ans) => Some(ans.to_string().to_lowercase()),
-        Err(_) => return None,
+    match select_mode.raw_prompt() {
+        Ok(ans) => Ok(ans.index),
+        Err(e) => Err(prompt_error(e)),
+    }
+}
+
+/// Fuzzy filterable list of the entries, returns the index of the chosen one
+/// or `None` when the user would rather type its ID
+pub fn select_entry(entries: Vec<String>, message: &str) -> Result<Option<usize>, KmhError> {
+    let type_id = entries.len();
+    let mut options = entries;
+    options.push("Type an ID".to_string());
+
+    let matcher = SkimMatcherV2::default().ignore_case();
+    let filter = |input: &str, _: &String, option: &str, i: usize| {
+        i == type_id || matcher.fuzzy_match(option, input).is_some()
     };
 
-    ans
+    let mut select_mode = Select::new(message, options).with_filter(&filter);
+    select_mode.vim_mode = false;
+    select_mode.help_message = Some("Type to filter");
+    select_mode.render_config = themecfg();
+
+    match select_mode.raw_prompt() {
+        Ok(ans) if ans.index == type_id => Ok(None),
+        Ok(ans) => Ok(Some(ans.index)),
+        Err(e) => Err(prompt_error(e)),
+    }
 }
 
-pub fn ask_password(message: &str, enable_confirmation: bool) -> Option<String> {
+pub fn ask_password(message: &str, enable_confirmation: bool) -> Result<String, KmhError> {
     let mut password_mode = Password::new(message);
 
     password_mode.help_message = Some("Ctrl + r for show password");
@@ -50,25 +100,15 @@ pub fn ask_password(message: &str, enable_confirmation: bool) -> Option<String>
     password_mode.enable_display_toggle = true;
     password_mode.enable_confirmation = enable_confirmation;
 
-    let ans = match password_mode.prompt() {
-        Ok(r) => r,
-        Err(_) => return None,
-    };
-
-    Some(ans)
+    password_mode.prompt().map_err(prompt_error)
 }
 
-pub fn ask(message: &str) -> Option<String> {
+pub fn ask(message: &str) -> Result<String, KmhError> {
     let mut ask_mode = Text::new(message);
 
     ask_mode.render_config = themecfg();
 
-    let ans = match ask_mode.prompt() {
-        Ok(r) => r,
-        Err(_) => return None,
-    };
-
-    Some(ans)
+    ask_mode.prompt().map_err(prompt_error)
 }
 
 pub fn tree_classic(title: &str, elements: Vec<&str>) {
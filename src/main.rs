@@ -2,578 +2,2455 @@ use std::{
     fs,
     io::{Read, Write},
     process,
+    time::Duration,
 };
 
 use arboard::Clipboard;
+use chrono::{DateTime, SecondsFormat, Utc};
 use clap::Parser;
+use colored::Colorize;
+use crypto::{
+    cipher::{self, VaultCipher},
+    header::VaultHeader,
+    kdf::{self, Kdf},
+    keyfile, password, recipient,
+    slot::Keyring,
+    Credentials,
+};
+use error::{ErrorFormat, KmhError};
+use fields::{CustomField, FieldType};
+use fuzzy_matcher::{skim::SkimMatcherV2, FuzzyMatcher};
+use output::{Output, OutputFormat};
+use regex::RegexBuilder;
 use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
 
 mod cli;
 mod crypto;
+mod error;
 mod export;
+mod fields;
+mod groups;
 mod interactive;
+mod output;
+mod urls;
 
 #[derive(Parser)]
 struct Cli {
     #[command(subcommand)]
     command: cli::Actions,
+    /// How errors are written on stderr: text or json
+    #[arg(long, global = true, default_value = "text")]
+    error_format: String,
+    #[command(flatten)]
+    display: cli::OutputArgs,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct JsonDatabseKMH {
+    /// Stays the same when other entries are removed, unlike the position.
+    /// Entries of older vaults get one derived from their position, title and username
+    /// until the next save keeps it, see `parse_database`
+    #[serde(default)]
+    id: Uuid,
     title: String,
     username: String,
     password: String,
     notes: String,
+    /// Path of its group like `Work/AWS/prod`, empty for the root of the vault
+    #[serde(default)]
+    group: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Sites the entry logs into, see `kmh find-url`
+    #[serde(default)]
+    urls: Vec<String>,
+    /// Unknown (`None`) for the entries of older vaults
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    modified_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    password_changed_at: Option<DateTime<Utc>>,
+    /// Previous passwords, the most recent first
+    #[serde(default)]
+    history: Vec<PasswordRevision>,
+    #[serde(default)]
+    fields: Vec<CustomField>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PasswordRevision {
+    password: String,
+    /// When it stopped being the password of the entry
+    replaced_at: DateTime<Utc>,
+}
+
+/// Settings of the whole vault, encrypted with the entries
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct VaultSettings {
+    /// How many previous passwords each entry keeps
+    max_history: usize,
 }
 
-#[derive(Debug)]
-enum Encryption {
-    AES256GCM,
-    SALSA20,
-    CHACHA20,
+impl Default for VaultSettings {
+    fn default() -> Self {
+        VaultSettings {
+            max_history: DEFAULT_MAX_HISTORY,
+        }
+    }
+}
+
+/// What the vault encrypts
+#[derive(Deserialize)]
+struct StoredDatabase {
+    #[serde(default)]
+    settings: VaultSettings,
+    entries: Vec<JsonDatabseKMH>,
+}
+
+impl JsonDatabseKMH {
+    fn new(title: String, username: String, password: String, notes: String) -> JsonDatabseKMH {
+        let now = Some(Utc::now());
+        JsonDatabseKMH {
+            id: Uuid::new_v4(),
+            title,
+            username,
+            password,
+            notes,
+            group: String::new(),
+            tags: Vec::new(),
+            urls: Vec::new(),
+            created_at: now,
+            modified_at: now,
+            password_changed_at: now,
+            history: Vec::new(),
+            fields: Vec::new(),
+        }
+    }
+
+    /// Change the fields that are given and keep the dates up to date,
+    /// a field set to its current value isn't a change. The replaced password
+    /// goes to the history, which keeps `max_history` of them
+    fn update(
+        &mut self,
+        title: Option<String>,
+        username: Option<String>,
+        password: Option<String>,
+        notes: Option<String>,
+        max_history: usize,
+    ) {
+        let now = Some(Utc::now());
+        let mut changed = false;
+
+        for (field, value) in [
+            (&mut self.title, title),
+            (&mut self.username, username),
+            (&mut self.notes, notes),
+        ] {
+            if let Some(r) = value.filter(|r| r != field) {
+                *field = r;
+                changed = true;
+            }
+        }
+        if let Some(r) = password.filter(|r| *r != self.password) {
+            let previous = std::mem::replace(&mut self.password, r);
+            if !previous.is_empty() {
+                self.history.insert(
+                    0,
+                    PasswordRevision {
+                        password: previous,
+                        replaced_at: Utc::now(),
+                    },
+                );
+                self.history.truncate(max_history);
+            }
+            self.password_changed_at = now;
+            changed = true;
+        }
+
+        if changed {
+            self.modified_at = now;
+        }
+    }
+
+    /// Replace the custom fields, the modification date only moves if they differ
+    fn update_fields(&mut self, fields: Vec<CustomField>) {
+        if fields != self.fields {
+            self.fields = fields;
+            self.modified_at = Some(Utc::now());
+        }
+    }
+
+    /// Replace the URLs, like `update_fields`
+    fn update_urls(&mut self, urls: Vec<String>) {
+        if urls != self.urls {
+            self.urls = urls;
+            self.modified_at = Some(Utc::now());
+        }
+    }
+
+    /// Move the entry to another group, like `update_fields`
+    fn update_group(&mut self, group: String) {
+        if group != self.group {
+            self.group = group;
+            self.modified_at = Some(Utc::now());
+        }
+    }
+
+    /// Replace the tags, like `update_fields`
+    fn update_tags(&mut self, tags: Vec<String>) {
+        if tags != self.tags {
+            self.tags = tags;
+            self.modified_at = Some(Utc::now());
+        }
+    }
+
+    /// Closest match of the URLs of the entry for `page`
+    fn url_match(&self, page: &url::Url) -> Option<urls::Match> {
+        self.urls
+            .iter()
+            .filter_map(|r| urls::parse(r).ok())
+            .filter_map(|r| urls::matches(&r, page))
+            .max()
+    }
+
+    /// Make a previous password the current one again, the current one goes to the history.
+    /// Nothing changes if it already is the current one
+    fn restore(&mut self, revision: usize, max_history: usize) {
+        if self.history[revision].password == self.password {
+            return;
+        }
+
+        let revision = self.history.remove(revision);
+        self.update(None, None, Some(revision.password), None, max_history);
+    }
+
+    /// Text `kmh search` looks into, protected custom fields are left out
+    fn search_fields(&self) -> Vec<&str> {
+        let mut fields = vec![self.title.as_str(), &self.username, &self.notes];
+        fields.extend(self.urls.iter().map(|r| r.as_str()));
+        fields.extend(self.tags.iter().map(|r| r.as_str()));
+        fields.extend(
+            self.fields
+                .iter()
+                .filter(|r| !r.protected)
+                .map(|r| r.value.as_str()),
+        );
+        fields
+    }
 }
 
-#[derive(Debug)]
-enum LoginType {
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum LoginType {
     PASSWORD,
     FILE,
+    /// Password and keyfile together
+    COMPOSITE,
+    /// Private key of a recipient, see `kmh keygen`
+    IDENTITY,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 enum FormatExport {
     CSV,
 }
 
+/// How `kmh search` compares the query to the entries, case insensitive
+enum SearchMode {
+    Substring,
+    Regex,
+    /// Best matches first
+    Fuzzy,
+}
+
+enum Field {
+    Password,
+    Username,
+    Notes,
+    Group,
+    /// One per line
+    Urls,
+    /// One per line
+    Tags,
+    All,
+    /// Name of a custom field
+    Custom(String),
+}
+
 #[derive(Clone)]
 pub struct DBManage {
     db: Vec<JsonDatabseKMH>,
 }
 
+/// Namespace of the UUIDs given to the entries of vaults saved without them
+const LEGACY_UUID_NAMESPACE: Uuid = uuid::uuid!("5c1f8d52-6a3e-4b8e-9f0b-2d7c4e1a9b63");
+
+/// Previous passwords kept by each entry, unless the vault says otherwise
+const DEFAULT_MAX_HISTORY: usize = 10;
+
+/// How many times the credentials are asked before giving up
+const PASSWORD_ATTEMPTS: usize = 3;
+
 const E_ENCRYPTION_TYPE_DONT_EXIST: &str =
     "This encryption don't exist :(, use: `kmh list -e` for show available encryptions";
 
+const E_KDF_TYPE_DONT_EXIST: &str = "This key derivation don't exist :(, use: argon2id or scrypt";
+
+const E_ERROR_FORMAT_DONT_EXIST: &str = "This error format don't exist :(, use: text or json";
+
 const E_FORMAT_TYPE_DONT_EXIST: &str =
-    "This encryption don't exist :(, use: `kmh list -e` for show available encryptions";
+    "This export format don't exist :(, use: `kmh list -f` for show available formats";
 
-impl DBManage {
-    fn show(&self) {
-        let mut creds: Vec<Vec<String>> = Vec::new();
-
-        for (i, e) in self.db.iter().enumerate() {
-            let i_string = i.to_string();
-            let password = "*".repeat(e.password.len());
-            let tc = Vec::from([
-                i_string,
-                e.title.to_string(),
-                e.username.to_string(),
-                password.to_string(),
-                e.notes.to_string(),
-            ]);
-
-            creds.push(tc);
-        }
-
-        interactive::table(
-            vec!["ID", "Title", "Username", "Password", "Notes"],
-            creds,
-            '-',
-            5,
-        );
-    }
-}
+const E_OUTPUT_FORMAT_DONT_EXIST: &str =
+    "This output format don't exist :(, use: table, json, jsonl or tsv";
 
-fn main() {
-    let cli = Cli::parse();
-    let all_encryptions = vec!["aes256", "salsa20", "chacha20"];
-    let all_formats = vec!["csv"];
+const E_SEARCH_MODE_DONT_EXIST: &str =
+    "This search mode don't exist :(, use: substring, regex or fuzzy";
 
-    match &cli.command {
-        cli::Actions::Init(init) => {
-            init_db(&init.filename);
-        }
+const E_FIELD_DONT_EXIST: &str =
+    "This field don't exist :(, use: password, username, notes, group, urls, tags, all or the name of a custom field";
 
-        cli::Actions::Open(open) => {
-            open_db(&open.filename, &open.encryption, open.file);
-        }
+/// Columns of the entry listings
+const ENTRY_HEADERS: [&str; 10] = [
+    "ID", "UUID", "Group", "Title", "Username", "Password", "Notes", "URLs", "Tags", "Fields",
+];
 
-        cli::Actions::List(list) => {
-            if list.encryption {
-                interactive::tree_classic("Encryption list", all_encryptions);
-            }
-            if list.formatexport {
-                interactive::tree_classic("Export format list", all_formats);
-            }
-        }
-        cli::Actions::Export(export) => {
-            export_db(
-                &export.input,
-                &export.output,
-                &export.format,
-                &export.encryption,
-                export.keyfile,
-            );
-        }
-    }
+/// Added to `ENTRY_HEADERS` with `--timestamps`
+const TIMESTAMP_HEADERS: [&str; 3] = ["Created at", "Modified at", "Password changed at"];
 
-    return;
-}
+/// How long `kmh get --clip` keeps the clipboard content available
+const CLIP_TIMEOUT: Duration = Duration::from_secs(45);
 
-fn encrypt_database_password(encryption: Encryption, password: &String, filename: &String) {
-    match encryption {
-        Encryption::AES256GCM => {
-            let out = crypto::encrypt_database_aes(&vec![], &password).unwrap();
-            fs::File::create(filename)
-                .unwrap()
-                .write_all(out.as_slice())
-                .unwrap();
-        }
-        Encryption::SALSA20 => {
-            let out = crypto::encrypt_database_salsa20(&vec![], &password);
-            fs::File::create(filename)
-                .unwrap()
-                .write_all(out.as_slice())
-                .unwrap();
-        }
-        Encryption::CHACHA20 => {
-            let out = crypto::encrypt_database_chacha20(&vec![], &password);
-            fs::File::create(filename)
-                .unwrap()
-                .write_all(out.as_slice())
-                .unwrap();
+impl LoginType {
+    /// Login types new vaults and slots can use, recipients are added with `kmh share`
+    pub const CHOICES: [LoginType; 3] =
+        [LoginType::PASSWORD, LoginType::FILE, LoginType::COMPOSITE];
+
+    /// Identifier stored in the vault header
+    pub fn id(&self) -> u8 {
+        match self {
+            LoginType::PASSWORD => 1,
+            LoginType::FILE => 2,
+            LoginType::COMPOSITE => 3,
+            LoginType::IDENTITY => 4,
         }
     }
-}
 
-fn encrypt_database_file(encryption: Encryption, password_file: Vec<u8>, filename: &String) {
-    match encryption {
-        Encryption::AES256GCM => {
-            let out =
-                crypto::encrypt_database_aes(&vec![], &String::from_utf8(password_file).unwrap())
-                    .unwrap();
-            fs::File::create(filename)
-                .unwrap()
-                .write_all(out.as_slice())
-                .unwrap();
+    pub fn from_id(id: u8) -> Option<LoginType> {
+        match id {
+            1 => Some(LoginType::PASSWORD),
+            2 => Some(LoginType::FILE),
+            3 => Some(LoginType::COMPOSITE),
+            4 => Some(LoginType::IDENTITY),
+            _ => None,
         }
-        Encryption::SALSA20 => {
-            let out = crypto::encrypt_database_salsa20(
-                &vec![],
-                &String::from_utf8(password_file).unwrap(),
-            );
-            fs::File::create(filename)
-                .unwrap()
-                .write_all(out.as_slice())
-                .unwrap();
-        }
-        Encryption::CHACHA20 => {
-            let out = crypto::encrypt_database_chacha20(
-                &vec![],
-                &String::from_utf8(password_file).unwrap(),
-            );
-            fs::File::create(filename)
-                .unwrap()
-                .write_all(out.as_slice())
-                .unwrap();
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            LoginType::PASSWORD => "Password",
+            LoginType::FILE => "File",
+            LoginType::COMPOSITE => "Password + File",
+            LoginType::IDENTITY => "Identity",
         }
     }
 }
 
-fn decrypt_database(
-    encryption: &Encryption,
-    password: &String,
-    db: Vec<u8>,
-) -> Vec<JsonDatabseKMH> {
-    let decrypted_db = match encryption {
-        Encryption::AES256GCM => match crypto::decrypt_database_aes(db, &password) {
-            Ok(r) => Some(r),
-            Err(_) => None,
-        },
-        Encryption::SALSA20 => Some(crypto::decrypt_database_salsa20(db, &password)),
-        Encryption::CHACHA20 => Some(crypto::decrypt_database_chacha20(db, &password)),
-    };
-
-    // Deserialize DB
-    let json_db: Vec<JsonDatabseKMH> = serde_json::from_str(
-        String::from_utf8(decrypted_db.unwrap())
-            .expect("Bytes to String failed")
-            .as_str(),
-    )
-    .expect("Invalid JSON format");
-
-    json_db
+/// What the open menu shows: the entries of a group and its subgroups,
+/// only the ones with `tag` if there's one
+#[derive(Default)]
+struct View {
+    group: String,
+    tag: Option<String>,
+    /// Dates of the entries in the listings, `kmh open --timestamps`
+    timestamps: bool,
 }
 
-fn check_for_modify(str: &str) -> Option<String> {
-    if str.trim() != "" {
-        return Some(str.to_string());
+impl View {
+    fn output(&self) -> Output {
+        Output {
+            timestamps: self.timestamps,
+            ..Output::TABLE
+        }
     }
-    return None;
 }
 
-fn export_db(input: &String, output: &String, format: &String, encryption: &String, keyfile: bool) {
-    let mut fbuffer = Vec::new();
+/// A decrypted vault and what it was unlocked with
+struct UnlockedVault {
+    header: VaultHeader,
+    keyring: Keyring,
+    /// Index of the key slot the credentials opened
+    slot: usize,
+    credentials: Credentials,
+    db: Vec<JsonDatabseKMH>,
+    settings: VaultSettings,
+}
 
-    let fileformat = match format.to_lowercase().as_str() {
-        "csv" => FormatExport::CSV,
-        _ => {
-            eprintln!("{}", E_FORMAT_TYPE_DONT_EXIST);
-            return;
+impl DBManage {
+    /// Find an entry by ID, UUID, title (case insensitive) or fuzzy match on the title
+    fn find(&self, query: &str) -> Result<usize, KmhError> {
+        match self.find_exact(query) {
+            Err(KmhError::NotFound(_)) => {}
+            r => return r,
         }
-    };
 
-    let encryption_type = match encryption.to_lowercase().as_str() {
-        "aes256" => Encryption::AES256GCM,
-        "salsa20" => Encryption::SALSA20,
-        "chacha20" => Encryption::CHACHA20,
-        _ => {
-            eprintln!("{}", E_ENCRYPTION_TYPE_DONT_EXIST);
-            return;
+        let matcher = SkimMatcherV2::default();
+        let fuzzy: Vec<usize> = (0..self.db.len())
+            .filter(|&i| matcher.fuzzy_match(&self.db[i].title, query).is_some())
+            .collect();
+        self.only_match(query, fuzzy)
+    }
+
+    /// Like `find` without the fuzzy match, for the commands that change or remove the entry
+    fn find_exact(&self, query: &str) -> Result<usize, KmhError> {
+        if let Some(id) = self.by_id(query) {
+            return Ok(id);
         }
-    };
 
-    // Check if exist
-    if fs::metadata(input).is_err() {
-        eprintln!("File not found");
-        return;
+        let exact: Vec<usize> = (0..self.db.len())
+            .filter(|&i| self.db[i].title.to_lowercase() == query.to_lowercase())
+            .collect();
+        self.only_match(query, exact)
     }
 
-    // Open file
-    let mut hfile = match fs::File::open(input) {
-        Ok(r) => r,
-        Err(e) => {
-            println!("{}", e);
-            return;
+    /// Position of the entry with this ID, the short index shown in the tables, or UUID
+    fn by_id(&self, id: &str) -> Option<usize> {
+        if let Ok(i) = id.trim().parse::<usize>() {
+            return (i < self.db.len()).then_some(i);
         }
-    };
 
-    // Read file
-    match hfile.read_to_end(&mut fbuffer) {
-        Ok(r) => r,
-        Err(e) => {
-            println!("{}", e);
-            return;
+        let uuid = Uuid::parse_str(id.trim()).ok()?;
+        self.db.iter().position(|e| e.id == uuid)
+    }
+
+    fn only_match(&self, query: &str, matches: Vec<usize>) -> Result<usize, KmhError> {
+        match matches.as_slice() {
+            [] => Err(KmhError::NotFound(query.to_string())),
+            [id] => Ok(*id),
+            _ => Err(KmhError::Ambiguous(
+                query.to_string(),
+                matches
+                    .iter()
+                    .map(|&i| (i, self.db[i].title.clone()))
+                    .collect(),
+            )),
         }
-    };
+    }
 
-    let password: String = if keyfile {
-        let mut filebuf = Vec::new();
+    /// Row of the entry `id` for `output::print`, under `entry_headers`
+    fn row(&self, id: usize, output: Output) -> Vec<Value> {
+        let e = &self.db[id];
+        let mut row = vec![
+            json!(id),
+            json!(e.id),
+            json!(groups::display(&e.group)),
+            json!(e.title),
+            json!(e.username),
+            json!(output.password(&e.password)),
+            json!(e.notes),
+            json!(e.urls),
+            json!(e.tags),
+            Value::Object(
+                e.fields
+                    .iter()
+                    .map(|r| (r.name.clone(), json!(output.field(r))))
+                    .collect(),
+            ),
+        ];
 
-        let keyfile_path = match interactive::ask("Insert keyfile path:") {
-            Some(r) => r,
-            None => {
-                return;
-            }
-        };
-        match fs::metadata(&keyfile_path) {
-            Ok(_) => (),
-            Err(e) => {
-                eprintln!("Error: {}", e);
-                return;
-            }
+        if output.timestamps {
+            row.extend(
+                [e.created_at, e.modified_at, e.password_changed_at]
+                    .map(|r| json!(r.map(format_date))),
+            );
         }
-        fs::File::open(&keyfile_path)
-            .unwrap()
-            .read_to_end(&mut filebuf)
-            .unwrap();
+        row
+    }
 
-        String::from_utf8(filebuf).unwrap()
-    } else {
-        // Get password
-        match interactive::ask_password("password:", false) {
-            Some(r) => r,
-            None => {
-                return;
-            }
-        }
-    };
+    /// Show some entries, with their ID in the whole database
+    fn show_only(&self, ids: &[usize], output: Output) {
+        output::print(
+            output.format,
+            entry_headers(output),
+            ids.iter().map(|&i| self.row(i, output)).collect(),
+        );
+    }
 
-    let json_db = decrypt_database(&encryption_type, &password, fbuffer);
+    /// IDs of the entries in `group` or its subgroups, with `tag` if there's one
+    fn filter(&self, group: &str, tag: Option<&str>) -> Vec<usize> {
+        (0..self.db.len())
+            .filter(|&i| groups::contains(group, &self.db[i].group))
+            .filter(|&i| tag.is_none_or(|r| groups::has_tag(&self.db[i].tags, r)))
+            .collect()
+    }
 
-    // Init DB
-    let dbmanage = DBManage { db: json_db };
+    /// Groups directly under `group` that have entries, sorted
+    fn subgroups(&self, group: &str) -> Vec<String> {
+        let mut subgroups: Vec<String> = self
+            .db
+            .iter()
+            .filter_map(|e| groups::child(group, &e.group))
+            .map(|r| r.to_string())
+            .collect();
+        subgroups.sort();
+        subgroups.dedup();
+        subgroups
+    }
 
-    match fileformat {
-        FormatExport::CSV => export::csv_export(&dbmanage, output),
+    /// Every tag of the entries, once whatever its case, sorted
+    fn tags(&self) -> Vec<String> {
+        let mut tags: Vec<String> = Vec::new();
+        for tag in self.db.iter().flat_map(|e| &e.tags) {
+            if !groups::has_tag(&tags, tag) {
+                tags.push(tag.clone());
+            }
+        }
+        tags.sort_by_key(|r| r.to_lowercase());
+        tags
     }
-}
 
-fn init_db(filename: &String) {
-    let type_form = match interactive::select(
-        vec!["Password", "File"],
-        "What type of login do you want to use?",
-    ) {
-        Some(r) => r,
-        None => {
-            return;
+    /// IDs of the entries whose title, username, notes, URLs, tags or unprotected
+    /// custom fields match `query`, case insensitive
+    fn search(&self, query: &str, mode: SearchMode) -> Result<Vec<usize>, KmhError> {
+        match mode {
+            SearchMode::Substring => {
+                let query = query.to_lowercase();
+                Ok((0..self.db.len())
+                    .filter(|&i| {
+                        self.db[i]
+                            .search_fields()
+                            .iter()
+                            .any(|r| r.to_lowercase().contains(&query))
+                    })
+                    .collect())
+            }
+            SearchMode::Regex => {
+                let regex = RegexBuilder::new(query)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| KmhError::BadInput(format!("Invalid regex: {}", e)))?;
+                Ok((0..self.db.len())
+                    .filter(|&i| self.db[i].search_fields().iter().any(|r| regex.is_match(r)))
+                    .collect())
+            }
+            SearchMode::Fuzzy => {
+                let matcher = SkimMatcherV2::default().ignore_case();
+                let mut scored: Vec<(i64, usize)> = (0..self.db.len())
+                    .filter_map(|i| {
+                        self.db[i]
+                            .search_fields()
+                            .iter()
+                            .filter_map(|r| matcher.fuzzy_match(r, query))
+                            .max()
+                            .map(|score| (score, i))
+                    })
+                    .collect();
+                // Best score first, the order of the database otherwise
+                scored.sort_by_key(|&(score, i)| (std::cmp::Reverse(score), i));
+                Ok(scored.into_iter().map(|(_, i)| i).collect())
+            }
         }
-    };
+    }
+}
 
-    let ans = match interactive::select(
-        vec!["AES256 GCM", "Salsa20", "Chacha20-Poly1305"],
-        "Which cryptography do you want to use?",
-    ) {
+fn main() {
+    let cli = Cli::parse();
+
+    let error_format = match ErrorFormat::from_name(&cli.error_format) {
         Some(r) => r,
         None => {
-            return;
+            let e = KmhError::BadInput(E_ERROR_FORMAT_DONT_EXIST.to_string());
+            e.report(ErrorFormat::Text);
+            process::exit(e.exit_code());
         }
     };
 
-    // data = size of keufile or password
-    let (logintype, data) = match type_form.as_str() {
-        "password" => {
-            let password = match interactive::ask_password("Add a password:", true) {
-                Some(r) => r,
-                None => {
-                    return;
-                }
-            };
-            (LoginType::PASSWORD, password)
-        }
-        "file" => {
-            let size_file = match interactive::select(vec!["1024", "2048", "4096"], "Keyfile size")
-            {
-                Some(r) => r,
-                None => {
-                    return;
-                }
-            };
-            (LoginType::FILE, size_file)
+    if let Err(e) = run(&cli, error_format) {
+        e.report(error_format);
+        process::exit(e.exit_code());
+    }
+}
+
+fn run(cli: &Cli, error_format: ErrorFormat) -> Result<(), KmhError> {
+    let all_encryptions = cipher::available().map(|c| c.name()).collect();
+    let all_formats = vec!["csv"];
+
+    match &cli.command {
+        cli::Actions::Init(init) => {
+            let kdf = kdf_from_args(&init.kdf)?;
+            init_db(&init.filename, kdf)
         }
-        _ => {
-            return;
+
+        cli::Actions::Rekey(rekey) => {
+            let kdf = kdf_from_args(&rekey.kdf)?;
+            rekey_db(
+                &rekey.filename,
+                &rekey.encryption,
+                rekey.file,
+                &rekey.password,
+                &rekey.new_encryption,
+                kdf,
+            )
         }
-    };
 
-    match logintype {
-        LoginType::PASSWORD => match ans.as_str() {
-            "aes256 gcm" => {
-                encrypt_database_password(Encryption::AES256GCM, &data, filename);
-            }
-            "salsa20" => {
-                encrypt_database_password(Encryption::SALSA20, &data, filename);
+        cli::Actions::Slot(slot) => match &slot.action {
+            cli::SlotActions::Add(add) => {
+                let kdf = kdf_from_args(&add.kdf)?;
+                slot_add(&add.filename, &add.encryption, add.file, &add.password, kdf)
             }
-            "chacha20-poly1305" => {
-                encrypt_database_password(Encryption::CHACHA20, &data, filename);
-            }
-            _ => (),
+            cli::SlotActions::Remove(remove) => slot_remove(
+                &remove.filename,
+                remove.index,
+                remove.file,
+                &remove.password,
+            ),
+            cli::SlotActions::List(list) => slot_list(&list.filename, &list.encryption),
         },
-        LoginType::FILE => {
-            let size: usize = data.parse().unwrap();
-            let keyfile_filename = match interactive::ask("Insert keyfile name:") {
-                Some(r) => r,
-                None => {
-                    return;
-                }
-            };
 
-            let keycontent = crypto::generate_random_utf8(size);
+        cli::Actions::Keygen(keygen) => create_identity(&keygen.output),
 
-            fs::File::create(keyfile_filename)
-                .unwrap()
-                .write_all(keycontent.as_slice())
-                .unwrap();
+        cli::Actions::Share(share) => share_db(
+            &share.filename,
+            &share.recipient,
+            &share.copy,
+            share.force,
+            &share.encryption,
+            share.file,
+            &share.password,
+        ),
 
-            match ans.as_str() {
-                "aes256 gcm" => {
-                    encrypt_database_file(Encryption::AES256GCM, keycontent, filename);
-                }
-                "salsa20" => {
-                    encrypt_database_file(Encryption::SALSA20, keycontent, filename);
-                }
-                "chacha20-poly1305" => {
-                    encrypt_database_file(Encryption::CHACHA20, keycontent, filename);
-                }
-                _ => (),
+        cli::Actions::Open(open) => open_db(
+            &open.filename,
+            &open.encryption,
+            open.file,
+            &open.password,
+            cli.display.timestamps,
+            error_format,
+        ),
+
+        cli::Actions::List(list) => {
+            let output = output_from_args(&cli.display)?;
+            let (title, names) = if list.encryption {
+                ("Encryption list", all_encryptions)
+            } else {
+                ("Export format list", all_formats)
+            };
+
+            match output.format {
+                OutputFormat::Table => interactive::tree_classic(title, names),
+                format => output::print(
+                    format,
+                    vec!["Name"],
+                    names.into_iter().map(|r| vec![json!(r)]).collect(),
+                ),
             }
+            Ok(())
         }
+        cli::Actions::Show(show) => show_entries(show, output_from_args(&cli.display)?),
+        cli::Actions::Search(search) => search_entries(search, output_from_args(&cli.display)?),
+        cli::Actions::FindUrl(find) => find_url(find, output_from_args(&cli.display)?),
+        cli::Actions::Config(config) => config_db(config),
+        cli::Actions::Get(get) => get_entry(get, output_from_args(&cli.display)?),
+        cli::Actions::Add(add) => add_entry(
+            &add.filename,
+            &add.entry,
+            &add.encryption,
+            add.file,
+            &add.password,
+        ),
+        cli::Actions::Edit(edit) => edit_entry(
+            &edit.filename,
+            &edit.entry,
+            &edit.fields,
+            &edit.encryption,
+            edit.file,
+            &edit.password,
+        ),
+        cli::Actions::Rm(rm) => rm_entry(
+            &rm.filename,
+            &rm.entry,
+            &rm.encryption,
+            rm.file,
+            &rm.password,
+        ),
+        cli::Actions::Export(export) => export_db(
+            &export.input,
+            &export.output,
+            &export.format,
+            &export.encryption,
+            export.keyfile,
+            &export.password,
+        ),
     }
 }
 
-fn open_db(filename: &String, encryption: &String, keyfile: bool) {
-    let mut fbuffer = Vec::new();
+fn entry_headers(output: Output) -> Vec<&'static str> {
+    let mut headers = ENTRY_HEADERS.to_vec();
+    if output.timestamps {
+        headers.extend(TIMESTAMP_HEADERS);
+    }
+    headers
+}
 
-    let encryption_type = match encryption.to_lowercase().as_str() {
-        "aes256" => Encryption::AES256GCM,
-        "salsa20" => Encryption::SALSA20,
-        "chacha20" => Encryption::CHACHA20,
-        _ => {
-            eprintln!("{}", E_ENCRYPTION_TYPE_DONT_EXIST);
-            return;
+/// RFC 3339 in UTC, to the second
+fn format_date(date: DateTime<Utc>) -> String {
+    date.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+fn output_from_args(args: &cli::OutputArgs) -> Result<Output, KmhError> {
+    match OutputFormat::from_name(&args.output) {
+        Some(format) => Ok(Output {
+            format,
+            reveal: args.reveal,
+            timestamps: args.timestamps,
+        }),
+        None => Err(KmhError::BadInput(E_OUTPUT_FORMAT_DONT_EXIST.to_string())),
+    }
+}
+
+fn search_mode(name: &str) -> Result<SearchMode, KmhError> {
+    match name.to_lowercase().as_str() {
+        "substring" => Ok(SearchMode::Substring),
+        "regex" => Ok(SearchMode::Regex),
+        "fuzzy" => Ok(SearchMode::Fuzzy),
+        _ => Err(KmhError::BadInput(E_SEARCH_MODE_DONT_EXIST.to_string())),
+    }
+}
+
+fn kdf_from_args(args: &cli::KdfArgs) -> Result<Kdf, KmhError> {
+    let kdf = match args.kdf.to_lowercase().as_str() {
+        "argon2id" => Kdf::Argon2id {
+            memory: args.kdf_memory.unwrap_or(kdf::ARGON2ID_MEMORY),
+            iterations: args.kdf_iterations.unwrap_or(kdf::ARGON2ID_ITERATIONS),
+            parallelism: args.kdf_parallelism.unwrap_or(kdf::ARGON2ID_PARALLELISM),
+        },
+        "scrypt" => {
+            if args.kdf_iterations.is_some() {
+                return Err(KmhError::BadInput(
+                    "--kdf-iterations is not used by scrypt".to_string(),
+                ));
+            }
+
+            // scrypt uses 128 * r * N bytes, with r = 8 that is N KiB
+            let log_n = match args.kdf_memory {
+                Some(memory) if memory > 1 => memory.ilog2(),
+                Some(_) => return Err(KmhError::BadInput("--kdf-memory is too low".to_string())),
+                None => kdf::SCRYPT_LOG_N,
+            };
+
+            Kdf::Scrypt {
+                log_n,
+                r: kdf::SCRYPT_R,
+                p: args.kdf_parallelism.unwrap_or(kdf::SCRYPT_P),
+            }
         }
+        _ => return Err(KmhError::BadInput(E_KDF_TYPE_DONT_EXIST.to_string())),
     };
+    kdf.check_cost()
+        .map_err(|e| KmhError::BadInput(format!("Key derivation is too costly: {}", e)))?;
+
+    Ok(kdf)
+}
+
+/// Key derivation of new slots, its parameters come from the command line
+fn new_keyring_error(e: String) -> KmhError {
+    KmhError::BadInput(format!("Key derivation failed: {}", e))
+}
+
+fn encrypt_database(
+    encryption: &'static dyn VaultCipher,
+    db: &[JsonDatabseKMH],
+    settings: &VaultSettings,
+    keyring: &Keyring,
+) -> Result<Vec<u8>, KmhError> {
+    let dbstr = serde_json::to_vec(&json!({ "settings": settings, "entries": db }))
+        .expect("Invalid DB format");
+
+    crypto::encrypt_database(encryption, &dbstr, keyring)
+        .map_err(|_| KmhError::CorruptVault("Encryption failed".to_string()))
+}
+
+fn create_database(
+    encryption: &'static dyn VaultCipher,
+    kdf: Kdf,
+    credentials: &Credentials,
+    filename: &str,
+) -> Result<(), KmhError> {
+    let keyring = Keyring::new(credentials, kdf).map_err(new_keyring_error)?;
+
+    let out = encrypt_database(encryption, &[], &VaultSettings::default(), &keyring)?;
+    fs::write(filename, out).map_err(KmhError::io(filename))
+}
+
+/// Replace a vault without ever leaving it half written,
+/// the previous content is kept in `<filename>.bak`
+fn replace_database(filename: &str, content: &[u8]) -> Result<(), KmhError> {
+    let filename = vault_path(filename);
+    let backup_filename = format!("{}.bak", filename);
 
-    // Check if exist
-    if fs::metadata(filename).is_err() {
-        eprintln!("File not found");
-        return;
+    fs::copy(&filename, &backup_filename).map_err(KmhError::io(&backup_filename))?;
+    write_database(&filename, content)
+}
+
+/// File a vault path points to, renaming over a symlink would replace the link
+/// instead of the vault
+fn vault_path(filename: &str) -> String {
+    fs::canonicalize(filename)
+        .map(|r| r.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| filename.to_string())
+}
+
+/// Write a vault to `<filename>.tmp` then rename it: the key slots are in the header,
+/// an interrupted write would lose the only copy of the master key.
+/// The vault keeps its permissions, a new one is only readable by its owner
+fn write_database(filename: &str, content: &[u8]) -> Result<(), KmhError> {
+    let filename = vault_path(filename);
+    let tmp_filename = format!("{}.tmp", filename);
+    let permissions = fs::metadata(&filename).map(|r| r.permissions()).ok();
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut hfile = options
+        .open(&tmp_filename)
+        .map_err(KmhError::io(&tmp_filename))?;
+    if let Some(permissions) = permissions {
+        hfile
+            .set_permissions(permissions)
+            .map_err(KmhError::io(&tmp_filename))?;
     }
+    hfile
+        .write_all(content)
+        .and_then(|_| hfile.sync_all())
+        .map_err(KmhError::io(&tmp_filename))?;
+
+    fs::rename(&tmp_filename, &filename).map_err(KmhError::io(&filename))
+}
 
-    // Open file
-    let mut hfile = match fs::File::open(filename) {
+fn decrypt_database(
+    header: &VaultHeader,
+    keyring: &Keyring,
+    db: &[u8],
+) -> Result<(Vec<JsonDatabseKMH>, VaultSettings), KmhError> {
+    // The key slots already checked the key, old vaults have nothing but the decryption
+    let key_checked = !keyring.is_legacy();
+    let authenticated = header.cipher.replaced_by().is_none();
+
+    let decrypted_db = match crypto::decrypt_database(header, keyring, db) {
         Ok(r) => r,
-        Err(e) => {
-            eprintln!("{}", e);
-            return;
+        Err(_) if key_checked => {
+            return Err(KmhError::CorruptVault(
+                "the data doesn't match its authentication tag".to_string(),
+            ))
         }
+        Err(_) => return Err(KmhError::WrongKey),
     };
 
-    // Read file
-    match hfile.read_to_end(&mut fbuffer) {
-        Ok(r) => r,
-        Err(e) => {
-            println!("{}", e);
-            return;
+    // Deserialize DB, for the old unauthenticated ciphers this is the only sign of a wrong key
+    match parse_database(&decrypted_db) {
+        Ok(r) => Ok(r),
+        Err(e) if key_checked || authenticated => Err(KmhError::CorruptVault(e.to_string())),
+        Err(_) => Err(KmhError::WrongKey),
+    }
+}
+
+fn parse_database(db: &[u8]) -> serde_json::Result<(Vec<JsonDatabseKMH>, VaultSettings)> {
+    // Vaults saved before the settings existed only have the entries
+    let (mut entries, settings): (Vec<JsonDatabseKMH>, _) = if db.first() == Some(&b'[') {
+        (serde_json::from_slice(db)?, VaultSettings::default())
+    } else {
+        let stored: StoredDatabase = serde_json::from_slice(db)?;
+        (stored.entries, stored.settings)
+    };
+
+    // Same UUID on every load of a vault that was never saved since they exist,
+    // no secret goes in it since it is printed
+    for (i, entry) in entries.iter_mut().enumerate() {
+        if entry.id.is_nil() {
+            let name = json!([i, entry.title, entry.username]).to_string();
+            entry.id = Uuid::new_v5(&LEGACY_UUID_NAMESPACE, name.as_bytes());
         }
+    }
+    Ok((entries, settings))
+}
+
+fn check_for_modify(str: &str) -> Option<String> {
+    if str.trim() != "" {
+        return Some(str.to_string());
+    }
+    None
+}
+
+/// Read a vault file and figure out its encryption.
+/// The header is authoritative, `encryption` is only used for old headerless vaults
+fn read_vault(
+    filename: &str,
+    encryption: &Option<String>,
+) -> Result<(VaultHeader, Vec<u8>), KmhError> {
+    let encryption_flag = match encryption {
+        Some(e) => Some(
+            cipher::find(e)
+                .ok_or_else(|| KmhError::BadInput(E_ENCRYPTION_TYPE_DONT_EXIST.to_string()))?,
+        ),
+        None => None,
     };
 
-    let password: String = if keyfile {
-        let mut filebuf = Vec::new();
+    let fbuffer = fs::read(filename).map_err(KmhError::io(filename))?;
 
-        let keyfile_path = match interactive::ask("Insert keyfile path:") {
-            Some(r) => r,
-            None => {
-                return;
-            }
-        };
-        match fs::metadata(&keyfile_path) {
-            Ok(_) => (),
-            Err(e) => {
-                println!("Error: {}", e);
-                return;
+    match VaultHeader::parse(&fbuffer) {
+        Ok((header, ciphertext)) => {
+            if let Some(e) = encryption_flag {
+                if e.id() != header.cipher.id() {
+                    eprintln!(
+                        "Ignoring `-e {}`, this vault is encrypted with {}",
+                        e.name(),
+                        header.cipher.name()
+                    );
+                }
             }
+            let ciphertext = ciphertext.to_vec();
+            Ok((header, ciphertext))
         }
-        fs::File::open(&keyfile_path)
-            .unwrap()
-            .read_to_end(&mut filebuf)
-            .unwrap();
+        Err(crypto::header::HeaderError::Missing) => match encryption_flag {
+            Some(e) => Ok((VaultHeader::legacy(e), fbuffer)),
+            None => Err(KmhError::BadInput(
+                "This vault has no header, specify its encryption with `-e`".to_string(),
+            )),
+        },
+        Err(e) => Err(e.into()),
+    }
+}
 
-        String::from_utf8(filebuf).unwrap()
-    } else {
-        // Get password
-        match interactive::ask_password("password:", false) {
-            Some(r) => r,
-            None => {
-                return;
+/// Ask for the credentials of one of the key slots and decrypt the vault
+fn unlock_vault(
+    filename: &str,
+    encryption: &Option<String>,
+    keyfile: bool,
+    password: &cli::PasswordArgs,
+) -> Result<UnlockedVault, KmhError> {
+    let (header, fbuffer) = read_vault(filename, encryption)?;
+    let password = read_password(password)?;
+    let logintype = login_type(&header, keyfile, password.is_some())?;
+
+    let mut attempt = 1;
+    loop {
+        let credentials = ask_credentials(logintype, password.clone())?;
+
+        match try_unlock(&header, &fbuffer, &credentials) {
+            Ok((keyring, slot, db, settings)) => {
+                return Ok(UnlockedVault {
+                    header,
+                    keyring,
+                    slot,
+                    credentials,
+                    db,
+                    settings,
+                })
             }
+            // A password that doesn't come from the terminal would be wrong again
+            Err(KmhError::WrongKey) if password.is_none() && attempt < PASSWORD_ATTEMPTS => {
+                eprintln!(
+                    "{}, {} attempt(s) left",
+                    KmhError::WrongKey.to_string().yellow(),
+                    PASSWORD_ATTEMPTS - attempt
+                );
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
         }
-    };
+    }
+}
 
-    let json_db = decrypt_database(&encryption_type, &password, fbuffer);
+/// The key slots reject wrong credentials before anything is decrypted,
+/// old vaults without slots only find out when decrypting
+fn try_unlock(
+    header: &VaultHeader,
+    fbuffer: &[u8],
+    credentials: &Credentials,
+) -> Result<(Keyring, usize, Vec<JsonDatabseKMH>, VaultSettings), KmhError> {
+    let (keyring, slot) = Keyring::unlock(header.slots.clone(), credentials)
+        .map_err(|e| KmhError::CorruptVault(format!("Key derivation failed: {}", e)))?
+        .ok_or(KmhError::WrongKey)?;
 
-    // Init DB
-    let mut dbmanage = DBManage { db: json_db };
+    let (db, settings) = decrypt_database(header, &keyring, fbuffer)?;
 
-    loop {
+    Ok((keyring, slot, db, settings))
+}
+
+/// How to unlock the vault, asked when its slots use different login types.
+/// `keyfile` is only used for slots that don't record it, `has_password` picks
+/// a slot using the password given on the command line
+fn login_type(
+    header: &VaultHeader,
+    keyfile: bool,
+    has_password: bool,
+) -> Result<LoginType, KmhError> {
+    let mut logins = Vec::new();
+
+    for slot in &header.slots {
+        let login = match slot.login {
+            Some(r) => r,
+            None if keyfile => LoginType::FILE,
+            None => LoginType::PASSWORD,
+        };
+        if !logins.contains(&login) {
+            logins.push(login);
+        }
+    }
+
+    if has_password {
+        return [LoginType::PASSWORD, LoginType::COMPOSITE]
+            .into_iter()
+            .find(|r| logins.contains(r))
+            .ok_or_else(|| {
+                KmhError::BadInput("This vault isn't unlocked with a password".to_string())
+            });
+    }
+
+    match logins.as_slice() {
+        [] => Err(KmhError::CorruptVault(
+            "this vault has no key slot".to_string(),
+        )),
+        [login] => Ok(*login),
+        _ => ask_login_type(&logins, "How do you want to unlock the vault?"),
+    }
+}
+
+/// Move an old vault, whose key is derived from the credentials, to a random master key
+/// wrapped in a key slot. The key derivation is upgraded along the way if needed
+fn upgrade_keyring(vault: &mut UnlockedVault) -> Result<(), KmhError> {
+    if !vault.keyring.is_legacy() {
+        return Ok(());
+    }
+
+    let kdf = match vault.keyring.slots[vault.slot].kdf {
+        Kdf::Sha256 => Kdf::default(),
+        r => r,
+    };
+
+    vault.keyring = Keyring::new(&vault.credentials, kdf).map_err(new_keyring_error)?;
+    vault.slot = 0;
+    println!("Vault moved to key slots, key derivation: {}", kdf.name());
+
+    Ok(())
+}
+
+fn ask_keyfile() -> Result<Vec<u8>, KmhError> {
+    let keyfile_path = interactive::ask("Insert keyfile path:")?;
+    let filebuf = fs::read(&keyfile_path).map_err(KmhError::io(&keyfile_path))?;
+
+    keyfile::key_material(&filebuf).map_err(KmhError::BadInput)
+}
+
+/// Master password given with one of the `--password-*` options, `None` when it
+/// has to be asked in the terminal
+fn read_password(args: &cli::PasswordArgs) -> Result<Option<String>, KmhError> {
+    let output = if args.password_stdin {
+        let mut line = String::new();
+        std::io::stdin()
+            .read_line(&mut line)
+            .map_err(KmhError::io("stdin"))?;
+        line
+    } else if let Some(var) = &args.password_env {
+        eprintln!(
+            "{}",
+            "Warning: the password is visible to the processes that can read the environment, prefer --password-fd or --password-command".yellow()
+        );
+        std::env::var(var).map_err(|e| KmhError::BadInput(format!("${}: {}", var, e)))?
+    } else if let Some(fd) = args.password_fd {
+        // Also works on macOS, without touching the raw descriptor
+        let path = format!("/dev/fd/{}", fd);
+        fs::read_to_string(&path).map_err(KmhError::io(&path))?
+    } else if let Some(command) = &args.password_command {
+        password_command(command)?
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(output.lines().next().unwrap_or_default().to_string()))
+}
+
+/// Output of `command`, run by the shell so pipes and quotes work
+fn password_command(command: &str) -> Result<String, KmhError> {
+    let mut shell = if cfg!(windows) {
+        let mut r = process::Command::new("cmd");
+        r.arg("/C");
+        r
+    } else {
+        let mut r = process::Command::new("sh");
+        r.arg("-c");
+        r
+    };
+
+    let output = shell
+        .arg(command)
+        .stdin(process::Stdio::inherit())
+        .stderr(process::Stdio::inherit())
+        .output()
+        .map_err(KmhError::io(command))?;
+
+    if !output.status.success() {
+        return Err(KmhError::BadInput(format!(
+            "--password-command failed: {}",
+            output.status
+        )));
+    }
+
+    String::from_utf8(output.stdout)
+        .map_err(|_| KmhError::BadInput("--password-command output isn't UTF-8".to_string()))
+}
+
+fn ask_credentials(
+    logintype: LoginType,
+    password: Option<String>,
+) -> Result<Credentials, KmhError> {
+    let password = || match &password {
+        Some(r) => Ok(r.clone()),
+        None => interactive::ask_password("password:", false),
+    };
+
+    match logintype {
+        LoginType::PASSWORD => Ok(Credentials::Password(password()?)),
+        LoginType::FILE => Ok(Credentials::Keyfile(ask_keyfile()?)),
+        LoginType::COMPOSITE => {
+            let password = password()?;
+            let keyfile = ask_keyfile()?;
+            Ok(Credentials::Composite(password, keyfile))
+        }
+        LoginType::IDENTITY => Ok(Credentials::Identity(ask_identity()?)),
+    }
+}
+
+fn ask_identity() -> Result<recipient::Identity, KmhError> {
+    let identity_path = interactive::ask("Insert identity file path:")?;
+    let content = fs::read_to_string(&identity_path).map_err(KmhError::io(&identity_path))?;
+
+    recipient::Identity::parse(&content).map_err(KmhError::BadInput)
+}
+
+fn export_db(
+    input: &str,
+    output: &str,
+    format: &str,
+    encryption: &Option<String>,
+    keyfile: bool,
+    password: &cli::PasswordArgs,
+) -> Result<(), KmhError> {
+    let fileformat = match format.to_lowercase().as_str() {
+        "csv" => FormatExport::CSV,
+        _ => return Err(KmhError::BadInput(E_FORMAT_TYPE_DONT_EXIST.to_string())),
+    };
+
+    let vault = unlock_vault(input, encryption, keyfile, password)?;
+
+    // Init DB
+    let dbmanage = DBManage { db: vault.db };
+
+    match fileformat {
+        FormatExport::CSV => export::csv_export(&dbmanage, output),
+    }
+}
+
+fn get_entry(args: &cli::Get, output: Output) -> Result<(), KmhError> {
+    let field = match args.field.to_lowercase().as_str() {
+        "password" => Field::Password,
+        "username" => Field::Username,
+        "notes" => Field::Notes,
+        "group" => Field::Group,
+        "urls" => Field::Urls,
+        "tags" => Field::Tags,
+        "all" => Field::All,
+        _ => Field::Custom(args.field.clone()),
+    };
+    if args.clip && matches!(field, Field::All) {
+        return Err(KmhError::BadInput(
+            "--clip needs a single field".to_string(),
+        ));
+    }
+
+    let vault = unlock_vault(&args.filename, &args.encryption, args.file, &args.password)?;
+    let dbmanage = DBManage { db: vault.db };
+
+    let id = dbmanage.find(&args.entry)?;
+    let entry = &dbmanage.db[id];
+    let (name, value) = match field {
+        Field::Password => ("Password", entry.password.clone()),
+        Field::Username => ("Username", entry.username.clone()),
+        Field::Notes => ("Notes", entry.notes.clone()),
+        Field::Group => ("Group", groups::display(&entry.group).to_string()),
+        Field::Urls => ("URLs", entry.urls.join("\n")),
+        Field::Tags => ("Tags", entry.tags.join("\n")),
+        Field::Custom(name) => match entry
+            .fields
+            .iter()
+            .find(|r| r.name.to_lowercase() == name.to_lowercase())
+        {
+            Some(r) => (r.name.as_str(), r.value.clone()),
+            None => return Err(KmhError::BadInput(E_FIELD_DONT_EXIST.to_string())),
+        },
+        // The password is only printed with `--reveal` here, like in `kmh show`
+        Field::All if output.format == OutputFormat::Table => {
+            println!("uuid: {}", entry.id);
+            println!("group: {}", groups::display(&entry.group));
+            println!("title: {}", entry.title);
+            println!("username: {}", entry.username);
+            println!("password: {}", output.password(&entry.password));
+            println!("notes: {}", entry.notes);
+            for url in &entry.urls {
+                println!("url: {}", url);
+            }
+            if !entry.tags.is_empty() {
+                println!("tags: {}", entry.tags.join(", "));
+            }
+            for field in &entry.fields {
+                println!("{}: {}", field.name, output.field(field));
+            }
+            if output.timestamps {
+                for (name, date) in [
+                    ("created at", entry.created_at),
+                    ("modified at", entry.modified_at),
+                    ("password changed at", entry.password_changed_at),
+                ] {
+                    println!("{}: {}", name, date.map(format_date).unwrap_or_default());
+                }
+            }
+            return Ok(());
+        }
+        Field::All => {
+            output::print(
+                output.format,
+                entry_headers(output),
+                vec![dbmanage.row(id, output)],
+            );
+            return Ok(());
+        }
+    };
+
+    if args.clip {
+        clip_and_wait(value)?;
+    } else if output.format == OutputFormat::Table {
+        println!("{}", value);
+    } else {
+        output::print(output.format, vec![name], vec![vec![json!(value)]]);
+    }
+    Ok(())
+}
+
+/// Print the entries, or the ones of a group or tag, without the interactive session
+fn show_entries(args: &cli::Show, output: Output) -> Result<(), KmhError> {
+    let group = groups::normalize(args.group.as_deref().unwrap_or_default());
+
+    let vault = unlock_vault(&args.filename, &args.encryption, args.file, &args.password)?;
+    let dbmanage = DBManage { db: vault.db };
+
+    dbmanage.show_only(&dbmanage.filter(&group, args.tag.as_deref()), output);
+    Ok(())
+}
+
+/// Print the settings of the vault, or change the ones that are given
+fn config_db(args: &cli::Config) -> Result<(), KmhError> {
+    let mut vault = unlock_vault(&args.filename, &args.encryption, args.file, &args.password)?;
+
+    let Some(max_history) = args.max_history else {
+        println!("max history: {}", vault.settings.max_history);
+        return Ok(());
+    };
+
+    vault.settings.max_history = max_history;
+    let mut db = std::mem::take(&mut vault.db);
+    for entry in &mut db {
+        entry.history.truncate(max_history);
+    }
+    save_vault(&args.filename, &mut vault, &db)?;
+
+    println!("Each entry keeps {} previous password(s)", max_history);
+    Ok(())
+}
+
+fn find_url(args: &cli::FindUrl, output: Output) -> Result<(), KmhError> {
+    let page = urls::parse(&args.url).map_err(KmhError::BadInput)?;
+
+    let vault = unlock_vault(&args.filename, &args.encryption, args.file, &args.password)?;
+    let dbmanage = DBManage { db: vault.db };
+
+    let mut found: Vec<(urls::Match, usize)> = (0..dbmanage.db.len())
+        .filter_map(|i| Some((dbmanage.db[i].url_match(&page)?, i)))
+        .collect();
+    // Other programs get an empty list, not an error
+    if found.is_empty() && output.format == OutputFormat::Table {
+        return Err(KmhError::NotFound(args.url.clone()));
+    }
+    // Closest match first, the order of the database otherwise
+    found.sort_by_key(|&(quality, i)| (std::cmp::Reverse(quality), i));
+
+    let mut headers = entry_headers(output);
+    headers.push("Match");
+    let rows = found
+        .into_iter()
+        .map(|(quality, i)| {
+            let mut row = dbmanage.row(i, output);
+            row.push(json!(quality.name()));
+            row
+        })
+        .collect();
+
+    output::print(output.format, headers, rows);
+    Ok(())
+}
+
+fn search_entries(args: &cli::Search, output: Output) -> Result<(), KmhError> {
+    let mode = search_mode(&args.mode)?;
+
+    let vault = unlock_vault(&args.filename, &args.encryption, args.file, &args.password)?;
+    let dbmanage = DBManage { db: vault.db };
+
+    let ids = dbmanage.search(&args.query, mode)?;
+    if ids.is_empty() && output.format == OutputFormat::Table {
+        return Err(KmhError::NotFound(args.query.clone()));
+    }
+    dbmanage.show_only(&ids, output);
+    Ok(())
+}
+
+/// Password of an entry from `--stdin` or `--generate`, None if neither is given
+fn entry_password(args: &cli::EntryArgs) -> Result<Option<String>, KmhError> {
+    if args.generate {
+        if args.length == 0 {
+            return Err(KmhError::BadInput(
+                "--length must be at least 1".to_string(),
+            ));
+        }
+        return Ok(Some(password::generate(args.length)));
+    }
+
+    if args.stdin {
+        let mut input = String::new();
+        std::io::stdin()
+            .read_to_string(&mut input)
+            .map_err(KmhError::io("stdin"))?;
+        return Ok(Some(input.trim_end_matches(['\r', '\n']).to_string()));
+    }
+
+    Ok(None)
+}
+
+/// `tags` after the `--remove-tag` and `--tag` of `args`
+fn apply_tags(args: &cli::EntryArgs, mut tags: Vec<String>) -> Result<Vec<String>, KmhError> {
+    for tag in &args.remove_tag {
+        let count = tags.len();
+        tags.retain(|r| r.to_lowercase() != tag.trim().to_lowercase());
+        if tags.len() == count {
+            return Err(KmhError::BadInput(format!("No tag `{}`", tag.trim())));
+        }
+    }
+    for tag in &args.tag {
+        let tag = groups::tag(tag).map_err(KmhError::BadInput)?;
+        if !groups::has_tag(&tags, &tag) {
+            tags.push(tag);
+        }
+    }
+
+    Ok(tags)
+}
+
+/// Tags typed in the open menu, separated by commas
+fn ask_tags(message: &str) -> Result<Option<Vec<String>>, KmhError> {
+    let ans = interactive::ask(message)?;
+    if ans.trim().is_empty() {
+        return Ok(None);
+    }
+
+    let mut tags: Vec<String> = Vec::new();
+    for tag in ans.split(',').filter(|r| !r.trim().is_empty()) {
+        let tag = groups::tag(tag).map_err(KmhError::BadInput)?;
+        if !groups::has_tag(&tags, &tag) {
+            tags.push(tag);
+        }
+    }
+    Ok(Some(tags))
+}
+
+/// `urls` after the `--remove-url` and `--url` of `args`, URLs are stored normalized
+fn apply_urls(args: &cli::EntryArgs, mut urls: Vec<String>) -> Result<Vec<String>, KmhError> {
+    for url in &args.remove_url {
+        let url = normalize_url(url)?;
+        let count = urls.len();
+        urls.retain(|r| *r != url);
+        if urls.len() == count {
+            return Err(KmhError::BadInput(format!("No URL {}", url)));
+        }
+    }
+    for url in &args.url {
+        let url = normalize_url(url)?;
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+
+    Ok(urls)
+}
+
+fn normalize_url(url: &str) -> Result<String, KmhError> {
+    urls::parse(url)
+        .map(|r| r.to_string())
+        .map_err(KmhError::BadInput)
+}
+
+/// URLs typed in the open menu, separated by spaces
+fn ask_urls(message: &str) -> Result<Option<Vec<String>>, KmhError> {
+    let ans = interactive::ask(message)?;
+    if ans.trim().is_empty() {
+        return Ok(None);
+    }
+
+    ans.split_whitespace()
+        .map(normalize_url)
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+/// Custom fields given with `--custom`, checked before the vault is unlocked
+fn custom_fields(args: &cli::EntryArgs) -> Result<Vec<CustomField>, KmhError> {
+    args.custom
+        .iter()
+        .map(|r| CustomField::parse(r).map_err(KmhError::BadInput))
+        .collect()
+}
+
+/// `fields` after the `--remove-custom` and `--custom` of `args`
+fn apply_custom_fields(
+    args: &cli::EntryArgs,
+    custom: Vec<CustomField>,
+    mut fields: Vec<CustomField>,
+) -> Result<Vec<CustomField>, KmhError> {
+    for name in &args.remove_custom {
+        let count = fields.len();
+        fields.retain(|r| r.name.to_lowercase() != name.to_lowercase());
+        if fields.len() == count {
+            return Err(KmhError::BadInput(format!("No custom field `{}`", name)));
+        }
+    }
+    for field in custom {
+        fields::set(&mut fields, field);
+    }
+
+    Ok(fields)
+}
+
+/// Add, change or remove custom fields until the user is done
+fn ask_custom_fields(fields: &mut Vec<CustomField>) -> Result<(), KmhError> {
+    loop {
+        let mut choices = vec!["Done", "Add or change a custom field"];
+        if !fields.is_empty() {
+            choices.push("Remove a custom field");
+        }
+
+        match interactive::select(choices, "Custom fields:")?.as_str() {
+            "add or change a custom field" => {
+                let kind = interactive::select(FieldType::ALL.map(|r| r.name()).to_vec(), "Type:")?;
+                let kind = FieldType::from_name(&kind).unwrap_or(FieldType::Text);
+                let name = interactive::ask("Name:")?;
+                let value = match kind {
+                    FieldType::Hidden => interactive::ask_password("Value:", false)?,
+                    _ => interactive::ask("Value:")?,
+                };
+                let protected = kind == FieldType::Hidden
+                    || interactive::select(vec!["No", "Yes"], "Mask it like the password?")?
+                        == "yes";
+
+                // A wrong value only cancels this field
+                match CustomField::new(name, kind, value, protected) {
+                    Ok(r) => fields::set(fields, r),
+                    Err(e) => eprintln!("{}", e.yellow()),
+                }
+            }
+            "remove a custom field" => {
+                let names = fields.iter().map(|r| r.name.as_str()).collect();
+                let name = interactive::select(names, "Field:")?;
+                fields.retain(|r| r.name.to_lowercase() != name);
+            }
+            _ => return Ok(()),
+        }
+    }
+}
+
+/// Write the vault back, moving it to the current format first
+fn save_vault(
+    filename: &str,
+    vault: &mut UnlockedVault,
+    db: &[JsonDatabseKMH],
+) -> Result<(), KmhError> {
+    // Always written with a header, this also upgrades old headerless vaults
+    upgrade_keyring(vault)?;
+    if let Some(r) = vault.header.cipher.replaced_by() {
+        vault.header.cipher = r;
+        println!("Encryption upgraded to {}", r.name());
+    }
+
+    let edb = encrypt_database(vault.header.cipher, db, &vault.settings, &vault.keyring)?;
+    write_database(filename, &edb)
+}
+
+fn add_entry(
+    filename: &str,
+    args: &cli::EntryArgs,
+    encryption: &Option<String>,
+    keyfile: bool,
+    master: &cli::PasswordArgs,
+) -> Result<(), KmhError> {
+    let title = match &args.title {
+        Some(r) => r.clone(),
+        None => return Err(KmhError::BadInput("--title is required".to_string())),
+    };
+    let password = entry_password(args)?.unwrap_or_default();
+    let custom = custom_fields(args)?;
+    let urls = apply_urls(args, Vec::new())?;
+    let tags = apply_tags(args, Vec::new())?;
+
+    let mut vault = unlock_vault(filename, encryption, keyfile, master)?;
+    let mut db = std::mem::take(&mut vault.db);
+
+    let mut entry = JsonDatabseKMH::new(
+        title,
+        args.username.clone().unwrap_or_default(),
+        password,
+        args.notes.clone().unwrap_or_default(),
+    );
+    entry.fields = apply_custom_fields(args, custom, Vec::new())?;
+    entry.group = groups::normalize(args.group.as_deref().unwrap_or_default());
+    entry.tags = tags;
+    entry.urls = urls;
+    db.push(entry);
+    save_vault(filename, &mut vault, &db)?;
+
+    println!(
+        "Entry {} added, UUID: {}",
+        db.len() - 1,
+        db[db.len() - 1].id
+    );
+    Ok(())
+}
+
+fn edit_entry(
+    filename: &str,
+    query: &str,
+    args: &cli::EntryArgs,
+    encryption: &Option<String>,
+    keyfile: bool,
+    master: &cli::PasswordArgs,
+) -> Result<(), KmhError> {
+    let password = entry_password(args)?;
+    let custom = custom_fields(args)?;
+
+    let mut vault = unlock_vault(filename, encryption, keyfile, master)?;
+    let mut dbmanage = DBManage {
+        db: std::mem::take(&mut vault.db),
+    };
+
+    let id = dbmanage.find_exact(query)?;
+    let entry = &mut dbmanage.db[id];
+    entry.update(
+        args.title.clone(),
+        args.username.clone(),
+        password,
+        args.notes.clone(),
+        vault.settings.max_history,
+    );
+    let fields = apply_custom_fields(args, custom, entry.fields.clone())?;
+    entry.update_fields(fields);
+    let urls = apply_urls(args, entry.urls.clone())?;
+    entry.update_urls(urls);
+    let tags = apply_tags(args, entry.tags.clone())?;
+    entry.update_tags(tags);
+    if let Some(r) = &args.group {
+        entry.update_group(groups::normalize(r));
+    }
+    save_vault(filename, &mut vault, &dbmanage.db)?;
+
+    println!("Entry {} updated", id);
+    Ok(())
+}
+
+fn rm_entry(
+    filename: &str,
+    query: &str,
+    encryption: &Option<String>,
+    keyfile: bool,
+    password: &cli::PasswordArgs,
+) -> Result<(), KmhError> {
+    let mut vault = unlock_vault(filename, encryption, keyfile, password)?;
+    let mut dbmanage = DBManage {
+        db: std::mem::take(&mut vault.db),
+    };
+
+    let id = dbmanage.find_exact(query)?;
+    let entry = dbmanage.db.remove(id);
+    save_vault(filename, &mut vault, &dbmanage.db)?;
+
+    println!("Entry {} ({}) removed", id, entry.title);
+    Ok(())
+}
+
+/// On Linux the clipboard content is served by this process, so stay until it is
+/// replaced or `CLIP_TIMEOUT` is elapsed. Elsewhere the system keeps it, so wait
+/// `CLIP_TIMEOUT` and clear it, unless something else was copied meanwhile
+fn clip_and_wait(text: String) -> Result<(), KmhError> {
+    let mut clipboard = Clipboard::new().map_err(|e| KmhError::Clipboard(e.to_string()))?;
+
+    eprintln!(
+        "Copying to clipboard, it stays available for {} seconds",
+        CLIP_TIMEOUT.as_secs()
+    );
+
+    #[cfg(target_os = "linux")]
+    let copied = {
+        use arboard::SetExtLinux;
+        use std::time::Instant;
+
+        clipboard
+            .set()
+            .wait_until(Instant::now() + CLIP_TIMEOUT)
+            .text(text)
+    };
+    #[cfg(not(target_os = "linux"))]
+    let copied = clipboard.set_text(text.as_str()).and_then(|_| {
+        std::thread::sleep(CLIP_TIMEOUT);
+        match clipboard.get_text() {
+            Ok(r) if r == text => clipboard.clear(),
+            _ => Ok(()),
+        }
+    });
+
+    copied.map_err(|e| KmhError::Clipboard(e.to_string()))
+}
+
+fn ask_login_type(choices: &[LoginType], message: &str) -> Result<LoginType, KmhError> {
+    let type_form = interactive::select(choices.iter().map(|l| l.label()).collect(), message)?;
+
+    choices
+        .iter()
+        .copied()
+        .find(|l| l.label().to_lowercase() == type_form)
+        .ok_or_else(|| KmhError::BadInput(format!("Unknown login type: {}", type_form)))
+}
+
+/// Generate a new keyfile, or pick an existing one, and return its key material
+fn ask_new_keyfile() -> Result<Vec<u8>, KmhError> {
+    let source = interactive::select(
+        vec!["Generate a new keyfile", "Use an existing file"],
+        "Which keyfile do you want to use?",
+    )?;
+    if source == "use an existing file" {
+        return ask_keyfile();
+    }
+
+    let size_file = interactive::select(vec!["1024", "2048", "4096"], "Keyfile size")?;
+    let size: usize = size_file
+        .parse()
+        .map_err(|_| KmhError::BadInput(format!("Invalid keyfile size: {}", size_file)))?;
+    let keyfile_filename = interactive::ask("Insert keyfile name:")?;
+
+    let keycontent = keyfile::generate(size);
+
+    fs::write(&keyfile_filename, &keycontent).map_err(KmhError::io(&keyfile_filename))?;
+
+    Ok(keycontent)
+}
+
+/// Ask for a new password and/or keyfile
+fn ask_new_credentials(logintype: LoginType) -> Result<Credentials, KmhError> {
+    match logintype {
+        LoginType::PASSWORD => Ok(Credentials::Password(interactive::ask_password(
+            "Add a password:",
+            true,
+        )?)),
+        LoginType::FILE => Ok(Credentials::Keyfile(ask_new_keyfile()?)),
+        LoginType::COMPOSITE => {
+            let password = interactive::ask_password("Add a password:", true)?;
+            let keyfile = ask_new_keyfile()?;
+            Ok(Credentials::Composite(password, keyfile))
+        }
+        LoginType::IDENTITY => Err(KmhError::BadInput(
+            "Recipients are added with `kmh share`".to_string(),
+        )),
+    }
+}
+
+fn init_db(filename: &str, kdf: Kdf) -> Result<(), KmhError> {
+    let logintype = ask_login_type(
+        &LoginType::CHOICES,
+        "What type of login do you want to use?",
+    )?;
+
+    let ans = interactive::select(
+        cipher::available().map(|c| c.label()).collect(),
+        "Which cryptography do you want to use?",
+    )?;
+
+    let encryption = cipher::available()
+        .find(|c| c.label().to_lowercase() == ans)
+        .ok_or_else(|| KmhError::BadInput(E_ENCRYPTION_TYPE_DONT_EXIST.to_string()))?;
+
+    let credentials = ask_new_credentials(logintype)?;
+
+    create_database(encryption, kdf, &credentials, filename)
+}
+
+fn rekey_db(
+    filename: &str,
+    encryption: &Option<String>,
+    keyfile: bool,
+    password: &cli::PasswordArgs,
+    new_encryption: &Option<String>,
+    kdf: Kdf,
+) -> Result<(), KmhError> {
+    let new_encryption_flag = match new_encryption {
+        Some(e) => Some(
+            cipher::find(e)
+                .filter(|c| c.replaced_by().is_none())
+                .ok_or_else(|| KmhError::BadInput(E_ENCRYPTION_TYPE_DONT_EXIST.to_string()))?,
+        ),
+        None => None,
+    };
+
+    println!("Current credentials");
+    let mut vault = unlock_vault(filename, encryption, keyfile, password)?;
+    let cipher = vault.header.cipher;
+    let new_encryption = new_encryption_flag.unwrap_or(cipher.replaced_by().unwrap_or(cipher));
+
+    println!("New credentials");
+    let new_credentials = ask_new_credentials(ask_login_type(
+        &LoginType::CHOICES,
+        "What type of login do you want to use?",
+    )?)?;
+
+    // Only the slot that was used changes, the other ones keep working
+    if vault.keyring.is_legacy() {
+        vault.keyring = Keyring::new(&new_credentials, kdf).map_err(new_keyring_error)?;
+    } else {
+        vault
+            .keyring
+            .replace_slot(vault.slot, &new_credentials, kdf)
+            .map_err(new_keyring_error)?;
+    }
+
+    let edb = encrypt_database(new_encryption, &vault.db, &vault.settings, &vault.keyring)?;
+    replace_database(filename, &edb)?;
+
+    println!(
+        "Vault re-encrypted with {} and {}, the previous version is in {}.bak",
+        new_encryption.name(),
+        kdf.name(),
+        filename
+    );
+    Ok(())
+}
+
+fn slot_add(
+    filename: &str,
+    encryption: &Option<String>,
+    keyfile: bool,
+    password: &cli::PasswordArgs,
+    kdf: Kdf,
+) -> Result<(), KmhError> {
+    println!("Current credentials");
+    let mut vault = unlock_vault(filename, encryption, keyfile, password)?;
+    // Before asking for the new credentials
+    vault
+        .keyring
+        .check_slot_count()
+        .map_err(KmhError::BadInput)?;
+    upgrade_keyring(&mut vault)?;
+    let cipher = vault.header.cipher;
+    let cipher = cipher.replaced_by().unwrap_or(cipher);
+
+    println!("New credentials");
+    let new_credentials = ask_new_credentials(ask_login_type(
+        &LoginType::CHOICES,
+        "What type of login do you want to use?",
+    )?)?;
+
+    vault
+        .keyring
+        .add_slot(&new_credentials, kdf)
+        .map_err(new_keyring_error)?;
+
+    let edb = encrypt_database(cipher, &vault.db, &vault.settings, &vault.keyring)?;
+    replace_database(filename, &edb)?;
+
+    println!(
+        "Key slot {} added, the previous version is in {}.bak",
+        vault.keyring.slots.len() - 1,
+        filename
+    );
+    Ok(())
+}
+
+fn slot_remove(
+    filename: &str,
+    index: usize,
+    keyfile: bool,
+    password: &cli::PasswordArgs,
+) -> Result<(), KmhError> {
+    // Vaults without a header have a single key, whatever their encryption
+    let fbuffer = fs::read(filename).map_err(KmhError::io(filename))?;
+    let slot_count = match VaultHeader::parse(&fbuffer) {
+        Ok((header, _)) => header.slots.len(),
+        Err(crypto::header::HeaderError::Missing) => 1,
+        Err(e) => return Err(e.into()),
+    };
+    if index >= slot_count {
+        return Err(KmhError::BadInput(format!(
+            "Key slot {} doesn't exist, use: `kmh slot list`",
+            index
+        )));
+    }
+    if slot_count == 1 {
+        return Err(KmhError::BadInput(
+            "This vault has only one key slot, use `kmh rekey` to change it".to_string(),
+        ));
+    }
+
+    let mut vault = unlock_vault(filename, &None, keyfile, password)?;
+    vault.keyring.slots.remove(index);
+
+    let edb = encrypt_database(
+        vault.header.cipher,
+        &vault.db,
+        &vault.settings,
+        &vault.keyring,
+    )?;
+    write_database(filename, &edb)?;
+
+    // A backup would still open with the removed slot
+    let backup_filename = format!("{}.bak", vault_path(filename));
+    match fs::remove_file(&backup_filename) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(KmhError::Io(backup_filename, e))
+        }
+        _ => {}
+    }
+
+    println!("Key slot {} removed", index);
+    Ok(())
+}
+
+/// Show the key slots, no credentials needed since they are stored in clear in the header
+fn slot_list(filename: &str, encryption: &Option<String>) -> Result<(), KmhError> {
+    let (header, _) = read_vault(filename, encryption)?;
+
+    let slots = header
+        .slots
+        .iter()
+        .enumerate()
+        .map(|(i, slot)| {
+            vec![
+                i.to_string(),
+                slot.login.map_or("Unknown", |l| l.label()).to_string(),
+                slot.kdf.name().to_string(),
+            ]
+        })
+        .collect();
+
+    interactive::table(vec!["Slot", "Login", "KDF"], slots, '-', 5);
+    Ok(())
+}
+
+fn create_identity(output: &str) -> Result<(), KmhError> {
+    let identity = recipient::Identity::generate();
+
+    // Never overwrite an existing identity, the vaults shared with it would be lost
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // Only readable by its owner, it holds a private key
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    options
+        .open(output)
+        .and_then(|mut r| r.write_all(identity.to_file().as_bytes()))
+        .map_err(KmhError::io(output))?;
+
+    println!(
+        "Public key: {}",
+        recipient::encode_public(&identity.public_key())
+    );
+    Ok(())
+}
+
+fn share_db(
+    filename: &str,
+    recipients: &[String],
+    output: &Option<String>,
+    force: bool,
+    encryption: &Option<String>,
+    keyfile: bool,
+    password: &cli::PasswordArgs,
+) -> Result<(), KmhError> {
+    let public_keys = recipients
+        .iter()
+        .map(|r| recipient::parse_public(r).map_err(KmhError::BadInput))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // The copy has none of the slots of the database, writing it over the database locks you out
+    if let Some(output) = output {
+        let source = fs::canonicalize(filename).map_err(KmhError::io(filename))?;
+        if fs::canonicalize(output).is_ok_and(|r| r == source) {
+            return Err(KmhError::BadInput(format!(
+                "{} is the database to share, write the copy to another file",
+                output
+            )));
+        }
+    }
+
+    let mut vault = unlock_vault(filename, encryption, keyfile, password)?;
+    let cipher = vault.header.cipher;
+    let cipher = cipher.replaced_by().unwrap_or(cipher);
+
+    // A copy gets its own master key and only the recipient slots
+    if output.is_some() {
+        vault.keyring = Keyring::random();
+    } else {
+        upgrade_keyring(&mut vault)?;
+    }
+
+    for public_key in &public_keys {
+        vault
+            .keyring
+            .add_recipient(public_key)
+            .map_err(KmhError::BadInput)?;
+    }
+
+    let edb = encrypt_database(cipher, &vault.db, &vault.settings, &vault.keyring)?;
+
+    match output {
+        Some(output) => {
+            let mut options = fs::OpenOptions::new();
+            options.write(true);
+            if force {
+                options.create(true).truncate(true);
+            } else {
+                options.create_new(true);
+            }
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+            options
+                .open(output)
+                .and_then(|mut r| r.write_all(&edb))
+                .map_err(KmhError::io(output))?
+        }
+        None => replace_database(filename, &edb)?,
+    }
+
+    println!(
+        "{} recipient(s) can open {}",
+        public_keys.len(),
+        output.as_deref().unwrap_or(filename)
+    );
+    Ok(())
+}
+
+/// Pick one of the entries shown in a fuzzy filterable list, or any entry by typing its ID
+fn select_entry(dbmanage: &DBManage, view: &View) -> Result<usize, KmhError> {
+    let ids = dbmanage.filter(&view.group, view.tag.as_deref());
+    if ids.is_empty() {
+        return Err(KmhError::BadInput("No entry here".to_string()));
+    }
+
+    let entries = ids
+        .iter()
+        .map(|&i| {
+            let e = &dbmanage.db[i];
+            let title = match e.group.as_str() {
+                "" => e.title.clone(),
+                group => format!("{}/{}", group, e.title),
+            };
+            match e.username.as_str() {
+                "" => title,
+                username => format!("{} — {}", title, username),
+            }
+        })
+        .collect();
+
+    match interactive::select_entry(entries, "Entry:")? {
+        Some(i) => Ok(ids[i]),
+        None => ask_id(dbmanage),
+    }
+}
+
+/// Ask for a group path, `/` or nothing is the root
+fn ask_group(message: &str) -> Result<String, KmhError> {
+    Ok(groups::normalize(&interactive::ask(message)?))
+}
+
+/// Ask for the ID of an entry and check it exists
+fn ask_id(dbmanage: &DBManage) -> Result<usize, KmhError> {
+    let ans = interactive::ask("ID or UUID:")?;
+
+    dbmanage
+        .by_id(&ans)
+        .ok_or_else(|| KmhError::BadInput(format!("No entry with ID {}", ans.trim())))
+}
+
+fn open_db(
+    filename: &str,
+    encryption: &Option<String>,
+    keyfile: bool,
+    password: &cli::PasswordArgs,
+    timestamps: bool,
+    error_format: ErrorFormat,
+) -> Result<(), KmhError> {
+    let mut vault = unlock_vault(filename, encryption, keyfile, password)?;
+
+    // Init DB
+    let mut dbmanage = DBManage {
+        db: std::mem::take(&mut vault.db),
+    };
+    let mut view = View {
+        timestamps,
+        ..View::default()
+    };
+
+    loop {
         // Database interaction
-        dbmanage.show();
+        match &view.tag {
+            Some(tag) => println!("Group: {}, tag: {}", groups::display(&view.group), tag),
+            None => println!("Group: {}", groups::display(&view.group)),
+        }
+        let subgroups = dbmanage.subgroups(&view.group);
+        if !subgroups.is_empty() {
+            println!("Subgroups: {}", subgroups.join(", "));
+        }
+        dbmanage.show_only(
+            &dbmanage.filter(&view.group, view.tag.as_deref()),
+            view.output(),
+        );
 
-        let ans = match interactive::select(
+        let ans = interactive::select(
             vec![
                 "Add",
                 "Remove",
                 "Modify",
                 "Show password",
                 "Copy password",
+                "History",
+                "Search",
+                "Open group",
+                "Filter by tag",
+                "Move to group",
                 "Save",
                 "Export in csv",
                 "Exit",
             ],
             "What do you want to do?",
-        ) {
-            Some(r) => r,
-            None => return,
-        };
+        )?;
 
-        match ans.to_lowercase().as_str() {
-            "add" => {
-                let title = interactive::ask("Title:").unwrap();
-                let username = interactive::ask("Username:").unwrap();
-                let password_asked = interactive::ask_password("Password:", false).unwrap();
-                let notes = interactive::ask("Notes:").unwrap();
-
-                dbmanage.db.push(JsonDatabseKMH {
-                    title,
-                    username,
-                    password: password_asked,
-                    notes,
-                });
-            }
-            "remove" => {
-                let id = interactive::ask("ID:").unwrap().parse::<usize>().unwrap();
-                dbmanage.db.remove(id);
-            }
-            "modify" => {
-                let id = interactive::ask("ID:").unwrap().parse::<usize>().unwrap();
-                let id_selected = &mut dbmanage.db[id];
-                let title = interactive::ask("Titie").unwrap();
-                let username = interactive::ask("Username").unwrap();
-                let password = interactive::ask_password("Password", false).unwrap();
-                let notes = interactive::ask("Notes").unwrap();
-
-                match check_for_modify(title.as_str()) {
-                    Some(r) => id_selected.title = r,
-                    None => (),
-                };
-                match check_for_modify(username.as_str()) {
-                    Some(r) => id_selected.username = r,
-                    None => (),
-                }
-                match check_for_modify(password.as_str()) {
-                    Some(r) => id_selected.password = r,
-                    None => (),
-                }
-                match check_for_modify(notes.as_str()) {
-                    Some(r) => id_selected.notes = r,
-                    None => (),
-                }
+        if ans == "exit" {
+            println!("Exiting...");
+            return Ok(());
+        }
+
+        // A failed action leaves the session, and the unsaved changes, as they are
+        match menu_action(&ans, &mut dbmanage, &mut vault, &mut view, filename) {
+            Ok(_) => interactive::clear_screen(),
+            Err(KmhError::Cancelled) => interactive::clear_screen(),
+            Err(e) => e.report(error_format),
+        }
+    }
+}
+
+fn menu_action(
+    ans: &str,
+    dbmanage: &mut DBManage,
+    vault: &mut UnlockedVault,
+    view: &mut View,
+    filename: &str,
+) -> Result<(), KmhError> {
+    match ans {
+        "add" => {
+            let title = interactive::ask("Title:")?;
+            let username = interactive::ask("Username:")?;
+            let password_asked = interactive::ask_password("Password:", false)?;
+            let notes = interactive::ask("Notes:")?;
+            let urls = ask_urls("URLs, separated by spaces:")?;
+            let tags = ask_tags("Tags, separated by commas:")?;
+
+            // Added to the group being browsed
+            let mut entry = JsonDatabseKMH::new(title, username, password_asked, notes);
+            entry.group = view.group.clone();
+            entry.urls = urls.unwrap_or_default();
+            entry.tags = tags.unwrap_or_default();
+            ask_custom_fields(&mut entry.fields)?;
+            dbmanage.db.push(entry);
+        }
+        "remove" => {
+            let id = select_entry(dbmanage, view)?;
+            dbmanage.db.remove(id);
+        }
+        "modify" => {
+            let id = select_entry(dbmanage, view)?;
+            let title = interactive::ask("Titie")?;
+            let username = interactive::ask("Username")?;
+            let password = interactive::ask_password("Password", false)?;
+            let notes = interactive::ask("Notes")?;
+            let urls = ask_urls("URLs, separated by spaces")?;
+            let tags = ask_tags("Tags, separated by commas")?;
+
+            dbmanage.db[id].update(
+                check_for_modify(&title),
+                check_for_modify(&username),
+                check_for_modify(&password),
+                check_for_modify(&notes),
+                vault.settings.max_history,
+            );
+
+            if let Some(r) = urls {
+                dbmanage.db[id].update_urls(r);
             }
-            "show password" => {
-                let id = interactive::ask("ID:").unwrap().parse::<usize>().unwrap();
-                let id_selected = dbmanage.db.get(id).expect("don't exist");
-
-                println!("{}", id_selected.password);
-                print!("Press enter for continue");
-                std::io::stdout().flush().unwrap();
-                std::io::stdin().read_line(&mut String::new()).unwrap();
+            if let Some(r) = tags {
+                dbmanage.db[id].update_tags(r);
             }
-            "copy password" => {
-                let mut clipboard = Clipboard::new().unwrap();
 
-                let id = interactive::ask("ID:").unwrap().parse::<usize>().unwrap();
-                let id_selected = dbmanage.db.get(id).expect("don't exist").to_owned();
+            let mut fields = dbmanage.db[id].fields.clone();
+            ask_custom_fields(&mut fields)?;
+            dbmanage.db[id].update_fields(fields);
+        }
+        "show password" => {
+            let id = select_entry(dbmanage, view)?;
 
-                clipboard.set_text(id_selected.password).unwrap()
+            println!("{}", dbmanage.db[id].password);
+            print!("Press enter for continue");
+            std::io::stdout()
+                .flush()
+                .and_then(|_| std::io::stdin().read_line(&mut String::new()))
+                .map_err(KmhError::io("terminal"))?;
+        }
+        "copy password" => {
+            let id = select_entry(dbmanage, view)?;
+
+            Clipboard::new()
+                .and_then(|mut r| r.set_text(dbmanage.db[id].password.clone()))
+                .map_err(|e| KmhError::Clipboard(e.to_string()))?;
+        }
+        "history" => {
+            let id = select_entry(dbmanage, view)?;
+            let entry = &mut dbmanage.db[id];
+            if entry.history.is_empty() {
+                return Err(KmhError::BadInput(format!(
+                    "{} has no previous password",
+                    entry.title
+                )));
             }
-            "save" => {
-                let edb = match &encryption_type {
-                    Encryption::AES256GCM => {
-                        crypto::encrypt_database_aes(&dbmanage.db, &password).unwrap()
-                    }
-                    Encryption::SALSA20 => {
-                        crypto::encrypt_database_salsa20(&dbmanage.db, &password)
-                    }
-                    Encryption::CHACHA20 => {
-                        crypto::encrypt_database_chacha20(&dbmanage.db, &password)
-                    }
-                };
 
-                fs::OpenOptions::new()
-                    .write(true)
-                    .open(filename)
-                    .unwrap()
-                    .write_all(edb.as_slice())
-                    .unwrap();
+            interactive::table(
+                vec!["Revision", "Password", "Replaced at"],
+                entry
+                    .history
+                    .iter()
+                    .enumerate()
+                    .map(|(i, r)| {
+                        vec![
+                            i.to_string(),
+                            r.password.clone(),
+                            format_date(r.replaced_at),
+                        ]
+                    })
+                    .collect(),
+                '-',
+                5,
+            );
+
+            let ans = interactive::ask("Revision to restore (empty to go back):")?;
+            if ans.trim().is_empty() {
+                return Ok(());
             }
-            "export in csv" => {
-                let exportfilename = interactive::ask("Output file:").unwrap();
-                export::csv_export(&dbmanage, &exportfilename);
+            match ans.trim().parse::<usize>() {
+                Ok(revision) if revision < entry.history.len() => {
+                    entry.restore(revision, vault.settings.max_history)
+                }
+                _ => return Err(KmhError::BadInput(format!("No revision {}", ans.trim()))),
+            }
+        }
+        "search" => {
+            let mode = interactive::select(vec!["Substring", "Regex", "Fuzzy"], "Search mode:")?;
+            let query = interactive::ask("Search:")?;
+            let ids = dbmanage.search(&query, search_mode(&mode)?)?;
+
+            dbmanage.show_only(&ids, view.output());
+            print!("{} match(es), press enter for continue", ids.len());
+            std::io::stdout()
+                .flush()
+                .and_then(|_| std::io::stdin().read_line(&mut String::new()))
+                .map_err(KmhError::io("terminal"))?;
+        }
+        "open group" => {
+            // (label, path) of the groups reachable from here
+            let mut targets: Vec<(String, String)> = dbmanage
+                .subgroups(&view.group)
+                .into_iter()
+                .map(|r| {
+                    let path = groups::normalize(&format!("{}/{}", view.group, r));
+                    (r, path)
+                })
+                .collect();
+            if !view.group.is_empty() {
+                let parent = groups::parent(&view.group);
+                targets.push((
+                    format!(".. ({})", groups::display(parent)),
+                    parent.to_string(),
+                ));
+                // `..` already goes to the root from a group at the top
+                if !parent.is_empty() {
+                    targets.push(("/ (root)".to_string(), String::new()));
+                }
             }
-            "exit" => {
-                println!("Exiting...");
-                process::exit(0);
+            if targets.is_empty() {
+                return Err(KmhError::BadInput(
+                    "No group yet, use Move to group to create one".to_string(),
+                ));
             }
-            _ => return,
+
+            let labels = targets.iter().map(|r| r.0.clone()).collect();
+            let i = interactive::select_index(labels, "Group:")?;
+            view.group = targets.swap_remove(i).1;
+        }
+        "filter by tag" => {
+            let mut tags = vec!["All tags".to_string()];
+            tags.extend(dbmanage.tags());
+
+            view.tag = match interactive::select_index(tags.clone(), "Tag:")? {
+                0 => None,
+                i => Some(tags.swap_remove(i)),
+            };
+        }
+        "move to group" => {
+            let id = select_entry(dbmanage, view)?;
+            let group = ask_group("Group, like Work/AWS (empty for the root):")?;
+            dbmanage.db[id].update_group(group);
+        }
+        "save" => save_vault(filename, vault, &dbmanage.db)?,
+        "export in csv" => {
+            let exportfilename = interactive::ask("Output file:")?;
+            export::csv_export(dbmanage, &exportfilename)?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes_gcm::{
+        aead::{Aead, KeyInit},
+        Aes256Gcm,
+    };
+    use ring::digest::{digest, SHA256};
+    use salsa20::cipher::{KeyIvInit, StreamCipher};
+
+    /// Database of a vault written before headers: the entries only, encrypted with the
+    /// SHA-256 of the password and an all-zero nonce
+    const LEGACY_DB: &[u8] =
+        br#"[{"title":"GitHub","username":"alice","password":"hunter2","notes":"n"}]"#;
+
+    fn entry(password: &str) -> JsonDatabseKMH {
+        JsonDatabseKMH::new(
+            "title".to_string(),
+            "username".to_string(),
+            password.to_string(),
+            String::new(),
+        )
+    }
+
+    fn history(entry: &JsonDatabseKMH) -> Vec<&str> {
+        entry.history.iter().map(|r| r.password.as_str()).collect()
+    }
+
+    #[test]
+    fn password_history_keeps_max_history_revisions() {
+        let mut e = entry("");
+        for password in ["one", "two", "three", "four"] {
+            e.update(None, None, Some(password.to_string()), None, 2);
+        }
+        // The empty first password isn't a revision
+        assert_eq!(e.password, "four");
+        assert_eq!(history(&e), ["three", "two"]);
+
+        // Same password, no new revision
+        e.update(None, None, Some("four".to_string()), None, 2);
+        assert_eq!(history(&e), ["three", "two"]);
+    }
+
+    #[test]
+    fn restore_swaps_the_current_password_into_the_history() {
+        let mut e = entry("one");
+        e.update(None, None, Some("two".to_string()), None, 10);
+        e.update(None, None, Some("three".to_string()), None, 10);
+        assert_eq!(history(&e), ["two", "one"]);
+
+        e.restore(1, 10);
+        assert_eq!(e.password, "one");
+        assert_eq!(history(&e), ["three", "two"]);
+
+        // A revision equal to the current password isn't lost
+        e.history.insert(
+            0,
+            PasswordRevision {
+                password: "one".to_string(),
+                replaced_at: Utc::now(),
+            },
+        );
+        e.restore(0, 10);
+        assert_eq!(e.password, "one");
+        assert_eq!(history(&e), ["one", "three", "two"]);
+    }
+
+    fn write_legacy_vault(name: &str, content: &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("kmh-{}-{}.kmh", name, process::id()));
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn headerless_vaults_open_with_their_encryption() {
+        let key: [u8; 32] = digest(&SHA256, b"secret").as_ref().try_into().unwrap();
+
+        let aes = Aes256Gcm::new(&key.into())
+            .encrypt(&[0; 12].into(), LEGACY_DB)
+            .unwrap();
+        let mut salsa = LEGACY_DB.to_vec();
+        salsa20::Salsa20::new(&key.into(), &[0; 8].into()).apply_keystream(&mut salsa);
+        let mut chacha = LEGACY_DB.to_vec();
+        chacha20::ChaCha20::new(&key.into(), &[0; 12].into()).apply_keystream(&mut chacha);
+
+        for (encryption, content) in [("aes256", aes), ("salsa20", salsa), ("chacha20", chacha)] {
+            let filename = write_legacy_vault(encryption, &content);
+            assert!(read_vault(&filename, &None).is_err(), "{}", encryption);
+
+            let (header, fbuffer) = read_vault(&filename, &Some(encryption.to_string())).unwrap();
+            let password = Credentials::Password("secret".to_string());
+            let (_, _, db, _) = try_unlock(&header, &fbuffer, &password).unwrap();
+            assert_eq!(db.len(), 1, "{}", encryption);
+            assert_eq!(db[0].title, "GitHub");
+            assert_eq!(db[0].password, "hunter2");
+
+            let wrong = Credentials::Password("wrong".to_string());
+            let e = try_unlock(&header, &fbuffer, &wrong).err().unwrap();
+            assert_eq!(
+                e.exit_code(),
+                KmhError::WrongKey.exit_code(),
+                "{}",
+                encryption
+            );
+
+            fs::remove_file(filename).unwrap();
+        }
+    }
+
+    #[test]
+    fn entries_without_uuid_get_the_same_one_on_every_load() {
+        let db = br#"[{"title":"GitHub","username":"alice","password":"p","notes":""},
+            {"title":"GitHub","username":"alice","password":"p","notes":""}]"#;
+        let (first, _) = parse_database(db).unwrap();
+        let (second, _) = parse_database(db).unwrap();
+
+        let ids: Vec<Uuid> = first.iter().map(|r| r.id).collect();
+        assert!(ids.iter().all(|r| !r.is_nil()));
+        assert_ne!(ids[0], ids[1]);
+        assert_eq!(ids, second.iter().map(|r| r.id).collect::<Vec<_>>());
+
+        // Saved with the vault from then on
+        let keyring =
+            Keyring::new(&Credentials::Password("secret".to_string()), Kdf::Sha256).unwrap();
+        let cipher = cipher::available().next().unwrap();
+        let vault = encrypt_database(cipher, &first, &VaultSettings::default(), &keyring).unwrap();
+        let (header, ciphertext) = VaultHeader::parse(&vault).unwrap();
+        let saved = crypto::decrypt_database(&header, &keyring, ciphertext).unwrap();
+        let saved = String::from_utf8(saved).unwrap();
+        assert!(ids.iter().all(|r| saved.contains(&r.to_string())));
+
+        let (reloaded, _) = decrypt_database(&header, &keyring, ciphertext).unwrap();
+        assert_eq!(ids, reloaded.iter().map(|r| r.id).collect::<Vec<_>>());
+    }
+
+    fn search_db() -> DBManage {
+        let mut github = entry("p");
+        github.title = "GitHub".to_string();
+        github.notes = "personal account".to_string();
+        let mut gitlab = entry("p");
+        gitlab.title = "GitLab".to_string();
+        gitlab.urls = vec!["https://gitlab.com".to_string()];
+        gitlab.tags = vec!["Work".to_string()];
+        let mut aws = entry("p");
+        aws.title = "AWS".to_string();
+        aws.fields = vec![
+            CustomField::parse("account=work-github").unwrap(),
+            CustomField::parse("!pin=personal").unwrap(),
+        ];
+        DBManage {
+            db: vec![github, gitlab, aws],
+        }
+    }
+
+    #[test]
+    fn substring_search_looks_in_every_unprotected_field() {
+        let db = search_db();
+        assert_eq!(db.search("github", SearchMode::Substring).unwrap(), [0, 2]);
+        assert_eq!(db.search("GITLAB.COM", SearchMode::Substring).unwrap(), [1]);
+        assert_eq!(db.search("work", SearchMode::Substring).unwrap(), [1, 2]);
+        // The protected field of AWS isn't searched
+        assert_eq!(db.search("personal", SearchMode::Substring).unwrap(), [0]);
+        assert!(db
+            .search("nothing", SearchMode::Substring)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn regex_search() {
+        let db = search_db();
+        assert_eq!(
+            db.search("^git(hub|lab)$", SearchMode::Regex).unwrap(),
+            [0, 1]
+        );
+        assert_eq!(db.search(r"\.com$", SearchMode::Regex).unwrap(), [1]);
+
+        let e = db.search("git(", SearchMode::Regex).err().unwrap();
+        assert!(matches!(e, KmhError::BadInput(_)));
+    }
+
+    #[test]
+    fn fuzzy_search_puts_the_best_matches_first() {
+        let mut db = search_db();
+        let mut scattered = entry("p");
+        scattered.title = "Gaming item tracker hub".to_string();
+        db.db.insert(0, scattered);
+
+        // The exact title first, the letters spread over the first entry last
+        assert_eq!(db.search("github", SearchMode::Fuzzy).unwrap(), [1, 3, 0]);
+        assert_eq!(db.search("gtlb", SearchMode::Fuzzy).unwrap(), [2]);
+        assert!(db.search("zzz", SearchMode::Fuzzy).unwrap().is_empty());
+    }
+
+    #[test]
+    fn output_flags_go_before_or_after_the_command() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+
+        for args in [
+            ["kmh", "--output", "json", "list", "-e"],
+            ["kmh", "list", "-e", "--output", "json"],
+        ] {
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert_eq!(cli.display.output, "json");
         }
-        interactive::clear_screen();
     }
 }
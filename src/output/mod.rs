@@ -0,0 +1,140 @@
+use serde_json::{Map, Value};
+
+use crate::{fields::CustomField, interactive};
+
+/// How read commands print their result on stdout, see `--output`
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Jsonl,
+    Tsv,
+}
+
+impl OutputFormat {
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name.to_lowercase().as_str() {
+            "table" => Some(OutputFormat::Table),
+            "json" => Some(OutputFormat::Json),
+            "jsonl" => Some(OutputFormat::Jsonl),
+            "tsv" => Some(OutputFormat::Tsv),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Output {
+    pub format: OutputFormat,
+    /// Print the passwords instead of masking them
+    pub reveal: bool,
+    /// Add the dates of the entries to the listings
+    pub timestamps: bool,
+}
+
+impl Output {
+    /// Table used by the interactive session
+    pub const TABLE: Output = Output {
+        format: OutputFormat::Table,
+        reveal: false,
+        timestamps: false,
+    };
+
+    pub fn password(&self, password: &str) -> String {
+        if self.reveal {
+            password.to_string()
+        } else {
+            "*".repeat(password.len())
+        }
+    }
+
+    /// Value of a custom field, masked like the password when it's protected
+    pub fn field(&self, field: &CustomField) -> String {
+        if field.protected {
+            self.password(&field.value)
+        } else {
+            field.value.clone()
+        }
+    }
+}
+
+/// Print rows under `headers`. In json the headers, lowercased, are the keys of each object
+pub fn print(format: OutputFormat, headers: Vec<&str>, rows: Vec<Vec<Value>>) {
+    match format {
+        OutputFormat::Table => interactive::table(
+            headers,
+            rows.iter()
+                .map(|row| row.iter().map(cell).collect())
+                .collect(),
+            '-',
+            5,
+        ),
+        OutputFormat::Json => {
+            let objects: Vec<Value> = rows.into_iter().map(|r| object(&headers, r)).collect();
+            println!("{}", Value::Array(objects));
+        }
+        OutputFormat::Jsonl => {
+            for row in rows {
+                println!("{}", object(&headers, row));
+            }
+        }
+        OutputFormat::Tsv => {
+            println!("{}", headers.join("\t"));
+            for row in &rows {
+                let cells: Vec<String> = row.iter().map(|r| escape_tsv(&cell(r))).collect();
+                println!("{}", cells.join("\t"));
+            }
+        }
+    }
+}
+
+fn object(headers: &[&str], row: Vec<Value>) -> Value {
+    let map: Map<String, Value> = headers
+        .iter()
+        .map(|r| r.to_lowercase().replace(' ', "_"))
+        .zip(row)
+        .collect();
+    Value::Object(map)
+}
+
+/// Text of a cell, strings without their json quotes, arrays as `a, b`
+/// and objects as `key: value, ...`
+fn cell(value: &Value) -> String {
+    match value {
+        Value::String(r) => r.clone(),
+        Value::Null => String::new(),
+        Value::Object(map) => map
+            .iter()
+            .map(|(k, v)| format!("{}: {}", k, cell(v)))
+            .collect::<Vec<_>>()
+            .join(", "),
+        Value::Array(values) => values.iter().map(cell).collect::<Vec<_>>().join(", "),
+        r => r.to_string(),
+    }
+}
+
+/// Tabs and line breaks would split the cell, they are written like in C
+fn escape_tsv(cell: &str) -> String {
+    cell.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn json_objects_and_tsv_cells() {
+        let row = object(&["ID", "Title"], vec![json!(3), json!("GitHub")]);
+        assert_eq!(row, json!({ "id": 3, "title": "GitHub" }));
+
+        assert_eq!(escape_tsv("a\tb\nc\\"), "a\\tb\\nc\\\\");
+        assert_eq!(cell(&json!("text")), "text");
+        assert_eq!(cell(&json!(3)), "3");
+        assert_eq!(cell(&json!({ "PIN": "****", "Id": 7 })), "PIN: ****, Id: 7");
+        assert_eq!(cell(&json!(["a", "b"])), "a, b");
+    }
+}
@@ -0,0 +1,104 @@
+use url::{Host, Url};
+
+/// How well a saved URL matches a page, from the loosest to the closest
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Match {
+    /// Another subdomain of the same registrable domain, `login.example.com` for `www.example.com`
+    Domain,
+    /// Same host on another port, or saved as http for an https page
+    Host,
+    /// Same scheme, host and port
+    Origin,
+    /// Same origin and the page is under the saved path
+    Path,
+}
+
+impl Match {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Match::Domain => "domain",
+            Match::Host => "host",
+            Match::Origin => "origin",
+            Match::Path => "path",
+        }
+    }
+}
+
+/// Parse a URL typed by a user, `https://` is assumed without a scheme
+pub fn parse(input: &str) -> Result<Url, String> {
+    let input = input.trim();
+
+    // Not `Url::parse` first, `example.com:8080` would have `example.com` as scheme
+    let url = if input.contains("://") {
+        Url::parse(input)
+    } else {
+        Url::parse(&format!("https://{}", input))
+    }
+    .map_err(|e| format!("Invalid URL {}: {}", input, e))?;
+
+    if !url.has_host() {
+        return Err(format!("Invalid URL {}: no host", input));
+    }
+    Ok(url)
+}
+
+/// How `saved` matches `page`. A https URL is never offered to a page that isn't https
+pub fn matches(saved: &Url, page: &Url) -> Option<Match> {
+    let same_scheme = saved.scheme() == page.scheme();
+    let upgrade = saved.scheme() == "http" && page.scheme() == "https";
+    if !same_scheme && !upgrade {
+        return None;
+    }
+
+    if saved.host() != page.host() {
+        let domain = registrable_domain(saved)?;
+        return (Some(domain) == registrable_domain(page)).then_some(Match::Domain);
+    }
+    if upgrade || saved.port_or_known_default() != page.port_or_known_default() {
+        return Some(Match::Host);
+    }
+
+    let path = saved.path().trim_end_matches('/');
+    let under_path = page.path() == path || page.path().starts_with(&format!("{}/", path));
+    if !path.is_empty() && under_path {
+        Some(Match::Path)
+    } else {
+        Some(Match::Origin)
+    }
+}
+
+/// `example.co.uk` for `www.example.co.uk`, None for IP addresses and hosts like `localhost`
+fn registrable_domain(url: &Url) -> Option<&str> {
+    match url.host()? {
+        Host::Domain(domain) => addr::parse_domain_name(domain).ok()?.root(),
+        Host::Ipv4(_) | Host::Ipv6(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(saved: &str, page: &str) -> Option<Match> {
+        matches(&parse(saved).unwrap(), &parse(page).unwrap())
+    }
+
+    #[test]
+    fn match_quality() {
+        assert!(
+            check("github.com/login", "https://github.com/login?return=/") == Some(Match::Path)
+        );
+        assert!(check("github.com", "https://github.com/settings") == Some(Match::Origin));
+        assert!(check("github.com/login", "https://github.com/loginx") == Some(Match::Origin));
+        assert!(check("http://github.com", "https://github.com") == Some(Match::Host));
+        assert!(check("localhost:3000", "https://localhost:8080") == Some(Match::Host));
+        assert!(check("login.example.co.uk", "www.example.co.uk") == Some(Match::Domain));
+
+        assert!(check("https://github.com", "http://github.com").is_none());
+        assert!(check("example.co.uk", "other.co.uk").is_none());
+        assert!(check("192.168.1.1", "192.168.1.2").is_none());
+        assert!(check("localhost", "https://dev.localhost").is_none());
+
+        assert!(parse("http://[::1").is_err());
+    }
+}